@@ -0,0 +1,2 @@
+ALTER TABLE accounts
+ADD COLUMN suspended BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,6 @@
+DROP INDEX tags_devices_index;
+DROP INDEX device_type_id_devices_index;
+
+ALTER TABLE devices
+DROP COLUMN metadata,
+DROP COLUMN tags;
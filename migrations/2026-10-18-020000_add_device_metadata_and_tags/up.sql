@@ -0,0 +1,6 @@
+ALTER TABLE devices
+ADD COLUMN metadata JSONB NOT NULL DEFAULT '{}',
+ADD COLUMN tags VARCHAR[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX device_type_id_devices_index ON devices(device_type_id);
+CREATE INDEX tags_devices_index ON devices USING GIN (tags);
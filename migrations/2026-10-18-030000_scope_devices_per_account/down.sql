@@ -0,0 +1,6 @@
+ALTER TABLE devices
+DROP CONSTRAINT devices_pkey,
+ADD CONSTRAINT devices_pkey PRIMARY KEY (id);
+
+ALTER TABLE devices
+DROP COLUMN account_id;
@@ -0,0 +1,16 @@
+ALTER TABLE devices
+ADD COLUMN account_id VARCHAR;
+
+UPDATE devices
+SET account_id = device_types.account_id
+FROM device_types
+WHERE devices.device_type_id = device_types.id;
+
+ALTER TABLE devices
+ALTER COLUMN account_id SET NOT NULL;
+
+-- Device ids are chosen by customers, so they are
+-- only unique within an account
+ALTER TABLE devices
+DROP CONSTRAINT devices_pkey,
+ADD CONSTRAINT devices_pkey PRIMARY KEY (account_id, id);
@@ -0,0 +1,16 @@
+CREATE TABLE device_shadows (
+    account_id VARCHAR NOT NULL,
+    device_id VARCHAR NOT NULL,
+    reported JSONB NOT NULL DEFAULT '{}',
+    desired JSONB NOT NULL DEFAULT '{}',
+    version BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (account_id, device_id),
+    FOREIGN KEY (account_id, device_id) REFERENCES devices(account_id, id) ON DELETE CASCADE
+);
+
+CREATE TRIGGER set_timestamp
+BEFORE UPDATE ON device_shadows
+FOR EACH ROW
+EXECUTE PROCEDURE trigger_set_timestamp();
@@ -0,0 +1,2 @@
+DROP INDEX logs_data_index;
+DROP INDEX logs_account_id_created_at_index;
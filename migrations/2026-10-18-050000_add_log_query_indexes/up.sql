@@ -0,0 +1,6 @@
+-- Listing an account's logs is always ordered by created_at
+CREATE INDEX logs_account_id_created_at_index ON logs (account_id, created_at DESC);
+
+-- Filters on device_id, device_type_id and message are
+-- JSONB containment queries on data
+CREATE INDEX logs_data_index ON logs USING GIN (data jsonb_path_ops);
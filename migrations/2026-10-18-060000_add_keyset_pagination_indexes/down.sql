@@ -0,0 +1,7 @@
+DROP INDEX logs_account_id_created_at_id_index;
+CREATE INDEX logs_account_id_created_at_index ON logs (account_id, created_at DESC);
+
+DROP INDEX webhooks_account_id_created_at_id_index;
+DROP INDEX topics_account_id_created_at_id_index;
+DROP INDEX devices_account_id_created_at_id_index;
+DROP INDEX device_types_account_id_created_at_id_index;
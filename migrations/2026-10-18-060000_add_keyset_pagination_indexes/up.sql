@@ -0,0 +1,8 @@
+-- Lists are paged by (created_at, id) within an account
+CREATE INDEX device_types_account_id_created_at_id_index ON device_types (account_id, created_at DESC, id DESC);
+CREATE INDEX devices_account_id_created_at_id_index ON devices (account_id, created_at DESC, id DESC);
+CREATE INDEX topics_account_id_created_at_id_index ON topics (account_id, created_at DESC, id DESC);
+CREATE INDEX webhooks_account_id_created_at_id_index ON webhooks (account_id, created_at DESC, id DESC);
+
+DROP INDEX logs_account_id_created_at_index;
+CREATE INDEX logs_account_id_created_at_id_index ON logs (account_id, created_at DESC, id DESC);
@@ -0,0 +1,2 @@
+ALTER TABLE accounts DROP COLUMN log_retention_max_rows;
+ALTER TABLE accounts DROP COLUMN log_retention_days;
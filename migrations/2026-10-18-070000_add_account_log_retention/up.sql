@@ -0,0 +1,5 @@
+-- Logs older than log_retention_days, or beyond the newest
+-- log_retention_max_rows, are pruned. NULL means no limit,
+-- so existing accounts keep all their logs until they opt in.
+ALTER TABLE accounts ADD COLUMN log_retention_days INTEGER;
+ALTER TABLE accounts ADD COLUMN log_retention_max_rows INTEGER;
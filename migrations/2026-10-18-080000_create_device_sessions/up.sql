@@ -0,0 +1,17 @@
+-- One row per websocket connection, kept after the
+-- connection ends for uptime and flapping analysis
+CREATE TABLE device_sessions (
+    id VARCHAR PRIMARY KEY,
+    account_id VARCHAR NOT NULL,
+    device_id VARCHAR NOT NULL,
+    device_type_id VARCHAR NOT NULL,
+    peer_address VARCHAR,
+    connected_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    disconnected_at TIMESTAMP,
+    close_reason VARCHAR,
+    messages_in BIGINT NOT NULL DEFAULT 0,
+    messages_out BIGINT NOT NULL DEFAULT 0,
+    FOREIGN KEY (account_id, device_id) REFERENCES devices(account_id, id) ON DELETE CASCADE
+);
+
+CREATE INDEX device_sessions_device_connected_at_index ON device_sessions (account_id, device_id, connected_at DESC, id DESC);
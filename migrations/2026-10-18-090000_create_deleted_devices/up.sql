@@ -0,0 +1,8 @@
+-- Devices deleted through the API. The websocket handshake
+-- refuses to register them again until they are restored.
+CREATE TABLE deleted_devices (
+    account_id VARCHAR NOT NULL,
+    id VARCHAR NOT NULL,
+    deleted_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (account_id, id)
+);
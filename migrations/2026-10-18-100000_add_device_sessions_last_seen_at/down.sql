@@ -0,0 +1 @@
+ALTER TABLE device_sessions DROP COLUMN last_seen_at;
@@ -0,0 +1,4 @@
+-- Bumped while the server holding the session is alive,
+-- so a starting server only closes sessions whose server
+-- is gone and not those of other running servers
+ALTER TABLE device_sessions ADD COLUMN last_seen_at TIMESTAMP NOT NULL DEFAULT NOW();
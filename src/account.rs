@@ -18,9 +18,17 @@ pub struct SignupParams{
     pub password_confirmation: String,
 }
 
+// Limits a new account starts with
+pub struct NewAccountLimits {
+    pub max_requests_per_minute: i32,
+    pub max_connections: i32,
+    pub log_retention_days: Option<i32>,
+}
+
 pub fn create_account<'a>(
     account_id: &'a str,
     api_cipher_key: &'a str,
+    limits: &'a NewAccountLimits,
     conn: &PgConnection,
 ) -> Result<(), &'static str> {
     let rng = ring::rand::SystemRandom::new();
@@ -47,10 +55,17 @@ pub fn create_account<'a>(
         id: account_id,
         secret_key: &HEXUPPER.encode(&ciphertext),
         cipher_iv: &HEXUPPER.encode(&iv),
+        max_requests_per_minute: limits.max_requests_per_minute,
+        max_connections: limits.max_connections,
     };
 
+    // Set separately since a None field of an Insertable struct
+    // means the column default rather than no retention limit
     let result = diesel::insert_into(accounts::table)
-        .values(&new_api_key)
+        .values((
+            &new_api_key,
+            accounts::dsl::log_retention_days.eq(limits.log_retention_days),
+        ))
         .execute(conn);
 
     match result {
@@ -93,6 +108,7 @@ pub struct AccountData {
     pub id: String,
     pub max_requests_per_minute: i32,
     pub max_connections: i32,
+    pub suspended: bool,
     pub created_at: u64,
 }
 
@@ -109,6 +125,184 @@ pub fn get_account<'a>(
         id: result.id,
         max_requests_per_minute: result.max_requests_per_minute,
         max_connections: result.max_connections,
+        suspended: result.suspended,
         created_at: instant_to_seconds(result.created_at)
     })
 }
+
+#[derive(Deserialize, Debug)]
+pub struct AccountLimits {
+    pub max_requests_per_minute: Option<i32>,
+    pub max_connections: Option<i32>,
+}
+
+pub fn update_account_limits<'a>(
+    account_id: &'a str,
+    limits: &'a AccountLimits,
+    conn: &PgConnection,
+) -> Result<AccountData, diesel::result::Error> {
+    use crate::schema::accounts::dsl;
+
+    // Both limits are applied or neither is
+    conn.transaction(|| {
+        let target = dsl::accounts.filter(dsl::id.eq(account_id));
+        if let Some(max_requests_per_minute) = limits.max_requests_per_minute {
+            diesel::update(target)
+                .set(dsl::max_requests_per_minute.eq(max_requests_per_minute))
+                .execute(conn)?;
+        }
+        if let Some(max_connections) = limits.max_connections {
+            diesel::update(target)
+                .set(dsl::max_connections.eq(max_connections))
+                .execute(conn)?;
+        }
+
+        get_account(account_id, conn)
+    })
+}
+
+pub fn set_account_suspended<'a>(
+    account_id: &'a str,
+    suspended: bool,
+    conn: &PgConnection,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::accounts::dsl;
+
+    let updated = diesel::update(dsl::accounts.filter(dsl::id.eq(account_id)))
+        .set(dsl::suspended.eq(suspended))
+        .execute(conn)?;
+
+    match updated {
+        0 => Err(diesel::result::Error::NotFound),
+        _ => Ok(()),
+    }
+}
+
+// Accounts that don't exist aren't suspended, so a new
+// account can still be created
+pub fn is_suspended<'a>(
+    account_id: &'a str,
+    conn: &PgConnection,
+) -> Result<bool, diesel::result::Error> {
+    use crate::schema::accounts::dsl;
+
+    let suspended = dsl::accounts
+        .filter(dsl::id.eq(account_id))
+        .select(dsl::suspended)
+        .first::<bool>(conn)
+        .optional()?;
+    Ok(suspended.unwrap_or(false))
+}
+
+// Logs are pruned in the background once they are older than
+// days or beyond the newest max_rows, None means no limit
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogRetention {
+    pub days: Option<i32>,
+    pub max_rows: Option<i32>,
+}
+
+pub fn get_log_retention<'a>(
+    account_id: &'a str,
+    conn: &PgConnection,
+) -> Result<LogRetention, diesel::result::Error> {
+    use crate::schema::accounts::dsl;
+
+    let (days, max_rows) = dsl::accounts
+        .filter(dsl::id.eq(account_id))
+        .select((dsl::log_retention_days, dsl::log_retention_max_rows))
+        .first::<(Option<i32>, Option<i32>)>(conn)?;
+
+    Ok(LogRetention { days, max_rows })
+}
+
+pub fn update_log_retention<'a>(
+    account_id: &'a str,
+    retention: &'a LogRetention,
+    conn: &PgConnection,
+) -> Result<LogRetention, diesel::result::Error> {
+    use crate::schema::accounts::dsl;
+
+    let updated = diesel::update(dsl::accounts.filter(dsl::id.eq(account_id)))
+        .set((
+            dsl::log_retention_days.eq(retention.days),
+            dsl::log_retention_max_rows.eq(retention.max_rows),
+        ))
+        .execute(conn)?;
+
+    match updated {
+        0 => Err(diesel::result::Error::NotFound),
+        _ => get_log_retention(account_id, conn),
+    }
+}
+
+// Accounts that have any log retention limit set
+pub fn get_log_retentions(
+    conn: &PgConnection,
+) -> Result<Vec<(String, LogRetention)>, diesel::result::Error> {
+    use crate::schema::accounts::dsl;
+
+    let result = dsl::accounts
+        .filter(dsl::log_retention_days.is_not_null().or(dsl::log_retention_max_rows.is_not_null()))
+        .select((dsl::id, dsl::log_retention_days, dsl::log_retention_max_rows))
+        .load::<(String, Option<i32>, Option<i32>)>(conn)?;
+
+    Ok(result
+        .into_iter()
+        .map(|(id, days, max_rows)| (id, LogRetention { days, max_rows }))
+        .collect())
+}
+
+pub fn delete_account<'a>(
+    account_id: &'a str,
+    conn: &PgConnection,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::accounts;
+    use crate::schema::deleted_devices;
+    use crate::schema::device_types;
+    use crate::schema::devices;
+    use crate::schema::logs;
+    use crate::schema::topics;
+    use crate::schema::webhook_topics;
+    use crate::schema::webhooks;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        // Remove rows referencing the account's webhooks and
+        // topics before the webhooks and topics themselves
+        diesel::delete(webhook_topics::table.filter(
+            webhook_topics::dsl::webhook_id.eq_any(
+                webhooks::table
+                    .filter(webhooks::dsl::account_id.eq(account_id))
+                    .select(webhooks::dsl::id)
+            )))
+            .execute(conn)?;
+        diesel::delete(webhook_topics::table.filter(
+            webhook_topics::dsl::topic_id.eq_any(
+                topics::table
+                    .filter(topics::dsl::account_id.eq(account_id))
+                    .select(topics::dsl::id)
+            )))
+            .execute(conn)?;
+        diesel::delete(webhooks::table.filter(webhooks::dsl::account_id.eq(account_id)))
+            .execute(conn)?;
+        diesel::delete(topics::table.filter(topics::dsl::account_id.eq(account_id)))
+            .execute(conn)?;
+
+        diesel::delete(devices::table.filter(devices::dsl::account_id.eq(account_id)))
+            .execute(conn)?;
+        diesel::delete(device_types::table.filter(device_types::dsl::account_id.eq(account_id)))
+            .execute(conn)?;
+        diesel::delete(deleted_devices::table.filter(deleted_devices::dsl::account_id.eq(account_id)))
+            .execute(conn)?;
+
+        diesel::delete(logs::table.filter(logs::dsl::account_id.eq(account_id)))
+            .execute(conn)?;
+
+        let deleted = diesel::delete(accounts::table.filter(accounts::dsl::id.eq(account_id)))
+            .execute(conn)?;
+        match deleted {
+            0 => Err(diesel::result::Error::NotFound),
+            _ => Ok(()),
+        }
+    })
+}
@@ -1,8 +1,21 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
 use diesel::prelude::*;
 use openssl::symm::{encrypt, Cipher};
 use data_encoding::{HEXUPPER, HEXLOWER};
-use actix_web::{error, Error};
+use actix_service::{Service, Transform};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::error::BlockingError;
+use actix_web::{error, web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
 use actix_web_httpauth::extractors::basic::BasicAuth;
+use futures::future::{ok, err, Either, LocalBoxFuture, Ready};
+use futures::FutureExt;
+use serde_json::json;
+use tracing::Span;
 use diesel::pg::PgConnection;
 use openssl::memcmp;
 use openssl::hash::MessageDigest;
@@ -10,6 +23,13 @@ use openssl::pkey::PKey;
 use openssl::sign::Signer;
 
 use crate::models;
+use crate::account;
+use crate::db::DbPool;
+use crate::utils::{instant_to_seconds};
+
+// How long a client has to open GET /subscribe with
+// a token from POST /subscribe/token
+const SUBSCRIBE_TOKEN_LIFETIME: Duration = Duration::from_secs(300);
 
 fn validate_api_key<'a>(
     account_id: &'a str,
@@ -19,19 +39,33 @@ fn validate_api_key<'a>(
 ) -> bool {
     use crate::schema::accounts::dsl;
 
-    let result = dsl::accounts
+    let result = match dsl::accounts
         .filter(dsl::id.eq(account_id))
-        .first::<models::Account>(conn)
-        .expect("Error getting account information.");
+        .first::<models::Account>(conn) {
+        Ok(account) => account,
+        // An unknown account is treated the same as
+        // a bad api key
+        Err(_) => return false,
+    };
 
     let cipher = Cipher::aes_256_cbc();
-    let iv = &HEXUPPER.decode(result.cipher_iv.as_bytes()).unwrap();
-    let ciphertext = encrypt(
+    let iv = match HEXUPPER.decode(result.cipher_iv.as_bytes()) {
+        Ok(iv) => iv,
+        Err(_) => return false,
+    };
+    let key = match HEXLOWER.decode(api_cipher_key.as_bytes()) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let ciphertext = match encrypt(
         cipher,
-        &HEXLOWER.decode(api_cipher_key.as_bytes()).unwrap(),
+        &key,
         Some(&iv),
         &api_key.as_bytes()
-    ).expect("Error generating ciphertext");
+    ) {
+        Ok(c) => c,
+        Err(_) => return false,
+    };
 
     if HEXUPPER.encode(&ciphertext) == result.secret_key {
         return true;
@@ -40,7 +74,11 @@ fn validate_api_key<'a>(
 }
 
 pub fn authenticate_connection<'a>(auth: BasicAuth, api_cipher_key: &'a str, conn: &PgConnection) -> Result<String, Error> {
-    match validate_api_key(&auth.user_id(), &auth.password().unwrap(), api_cipher_key, conn) {
+    let password = match auth.password() {
+        Some(p) => p,
+        None => return Err(error::ErrorUnauthorized("Unauthorized")),
+    };
+    match validate_api_key(&auth.user_id(), password, api_cipher_key, conn) {
         true => Ok(auth.user_id().to_string()),
         false => Err(error::ErrorUnauthorized("Unauthorized"))
     }
@@ -61,5 +99,319 @@ pub fn verify_hmac_sigature<'a>(
     signer.update(time.as_bytes()).unwrap();
     let hmac = signer.sign_to_vec().unwrap();
 
-    memcmp::eq(&hmac, &HEXLOWER.decode(signature.as_bytes()).unwrap())
+    let signature = match HEXLOWER.decode(signature.as_bytes()) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    // memcmp::eq panics on slices of different lengths
+    if signature.len() != hmac.len() {
+        return false;
+    }
+    memcmp::eq(&hmac, &signature)
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingHeader(&'static str),
+    InvalidHeader(&'static str),
+    InvalidSignature,
+    Unauthenticated,
+    Expired,
+    Suspended,
+    Unavailable,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingHeader(name) => write!(f, "missing {} header", name),
+            AuthError::InvalidHeader(name) => write!(f, "invalid {} header", name),
+            AuthError::InvalidSignature => write!(f, "invalid signature"),
+            AuthError::Unauthenticated => write!(f, "request was not authenticated"),
+            AuthError::Expired => write!(f, "token has expired"),
+            AuthError::Suspended => write!(f, "account is suspended"),
+            AuthError::Unavailable => write!(f, "unable to check the account"),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Suspended => StatusCode::FORBIDDEN,
+            AuthError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let error = match self {
+            AuthError::Suspended => "forbidden",
+            AuthError::Unavailable => "unavailable",
+            _ => "unauthorized",
+        };
+        HttpResponse::build(self.status_code()).json(json!({
+            "error": error,
+            "reason": self.to_string(),
+        }))
+    }
+}
+
+// The account a request was signed for. Inserted into the
+// request extensions by the HmacAuth middleware and
+// available to handlers as an extractor.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedAccount {
+    pub account_id: String,
+}
+
+impl FromRequest for AuthenticatedAccount {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match req.extensions().get::<AuthenticatedAccount>() {
+            Some(account) => ok(account.clone()),
+            None => err(AuthError::Unauthenticated.into()),
+        }
+    }
+}
+
+fn header_value<'a>(req: &'a ServiceRequest, name: &'static str) -> Result<&'a str, AuthError> {
+    match req.headers().get(name) {
+        Some(value) => value.to_str().map_err(|_| AuthError::InvalidHeader(name)),
+        None => Err(AuthError::MissingHeader(name)),
+    }
+}
+
+// Returns the account the request was signed for
+fn authenticate_request<'a>(req: &'a ServiceRequest, hmac_key: &'a str) -> Result<String, AuthError> {
+    let account_id = header_value(req, "Account-Id")?;
+    let time = header_value(req, "Time")?;
+    let hmac_signature = header_value(req, "Herd-Webapp-Signature")?;
+    let path: &str = &req.uri().to_string();
+
+    if !verify_hmac_sigature(path, account_id, time, hmac_signature, hmac_key) {
+        return Err(AuthError::InvalidSignature);
+    }
+    Ok(account_id.to_string())
+}
+
+// Suspended accounts keep their data but can't use
+// the API until an admin resumes them. The lookup runs on
+// the blocking pool, not the worker handling requests.
+async fn active_account(account_id: String, pool: DbPool) -> Result<AuthenticatedAccount, AuthError> {
+    let result = web::block(move || {
+        let conn = pool.get().map_err(|_| AuthError::Unavailable)?;
+        match account::is_suspended(&account_id, &conn) {
+            Ok(false) => Ok(AuthenticatedAccount { account_id }),
+            Ok(true) => Err(AuthError::Suspended),
+            Err(_) => Err(AuthError::Unavailable),
+        }
+    }).await;
+    match result {
+        Ok(account) => Ok(account),
+        Err(BlockingError::Error(e)) => Err(e),
+        Err(BlockingError::Canceled) => Err(AuthError::Unavailable),
+    }
+}
+
+// Signs what a subscribe token carries. The prefix keeps
+// these apart from request signatures, whose signed path
+// always starts with /.
+fn sign_subscribe_token(account_id: &str, expires_at: &str, hmac_key: &str) -> Vec<u8> {
+    let key = PKey::hmac(hmac_key.as_bytes()).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+    signer.update(b"subscribe:").unwrap();
+    signer.update(account_id.as_bytes()).unwrap();
+    signer.update(b":").unwrap();
+    signer.update(expires_at.as_bytes()).unwrap();
+    signer.sign_to_vec().unwrap()
+}
+
+// Issues a token letting a client that can't sign requests,
+// such as a browser EventSource, open GET /subscribe for
+// the account. Returns the token and when it expires, in
+// seconds since the unix epoch.
+pub fn issue_subscribe_token(account_id: &str, hmac_key: &str) -> (String, u64) {
+    let expires_at = instant_to_seconds(SystemTime::now() + SUBSCRIBE_TOKEN_LIFETIME);
+    let signature = sign_subscribe_token(account_id, &expires_at.to_string(), hmac_key);
+    let token = format!("{}.{}.{}", account_id, expires_at, HEXLOWER.encode(&signature));
+    (token, expires_at)
+}
+
+// The token is only checked when the stream is opened, a
+// stream outlives the token
+pub async fn verify_subscribe_token(token: &str, hmac_key: &str, pool: &DbPool) -> Result<AuthenticatedAccount, AuthError> {
+    // Split from the right, the account id is whatever
+    // comes before the last two dots
+    let mut parts = token.rsplitn(3, '.');
+    let signature = parts.next().ok_or(AuthError::InvalidSignature)?;
+    let expires_at = parts.next().ok_or(AuthError::InvalidSignature)?;
+    let account_id = parts.next().ok_or(AuthError::InvalidSignature)?;
+
+    let signature = HEXLOWER.decode(signature.as_bytes()).map_err(|_| AuthError::InvalidSignature)?;
+    let expected = sign_subscribe_token(account_id, expires_at, hmac_key);
+    // memcmp::eq panics on slices of different lengths
+    if signature.len() != expected.len() || !memcmp::eq(&expected, &signature) {
+        return Err(AuthError::InvalidSignature);
+    }
+    let expires_at: u64 = expires_at.parse().map_err(|_| AuthError::InvalidSignature)?;
+    if expires_at <= instant_to_seconds(SystemTime::now()) {
+        return Err(AuthError::Expired);
+    }
+
+    active_account(account_id.to_string(), pool.clone()).await
+}
+
+// Middleware verifying the Herd-Webapp-Signature of
+// requests coming from the webapp
+pub struct HmacAuth {
+    hmac_key: String,
+    pool: DbPool,
+}
+
+impl HmacAuth {
+    pub fn new(hmac_key: String, pool: DbPool) -> HmacAuth {
+        HmacAuth { hmac_key, pool }
+    }
+}
+
+impl<S, B> Transform<S> for HmacAuth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = HmacAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(HmacAuthMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            hmac_key: self.hmac_key.clone(),
+            pool: self.pool.clone(),
+        })
+    }
+}
+
+pub struct HmacAuthMiddleware<S> {
+    // Shared with the future that calls it once the
+    // account is checked
+    service: Rc<RefCell<S>>,
+    hmac_key: String,
+    pool: DbPool,
+}
+
+impl<S, B> Service for HmacAuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let account_id = match authenticate_request(&req, &self.hmac_key) {
+            Ok(a) => a,
+            Err(e) => return err(e.into()).boxed_local(),
+        };
+        let service = self.service.clone();
+        let pool = self.pool.clone();
+        async move {
+            let account = active_account(account_id, pool).await?;
+            Span::current().record("account_id", &account.account_id.as_str());
+            req.extensions_mut().insert(account);
+            // Not borrowed while awaiting, other requests
+            // use the service meanwhile
+            let response = service.borrow_mut().call(req);
+            response.await
+        }.boxed_local()
+    }
+}
+
+// Checks the Authorization: Bearer header against a key
+// only operators hold
+fn authenticate_token(req: &ServiceRequest, key: &str) -> Result<(), AuthError> {
+    let authorization = header_value(req, "Authorization")?;
+    if !authorization.starts_with("Bearer ") {
+        return Err(AuthError::InvalidHeader("Authorization"));
+    }
+    let token = authorization[7..].as_bytes();
+    // memcmp::eq panics on slices of different lengths
+    if token.len() != key.len() || !memcmp::eq(token, key.as_bytes()) {
+        return Err(AuthError::InvalidSignature);
+    }
+    Ok(())
+}
+
+// Middleware guarding operator routes, i.e. /admin which
+// acts on any account given in the path, and /metrics
+pub struct TokenAuth {
+    key: String,
+}
+
+impl TokenAuth {
+    pub fn new(key: String) -> TokenAuth {
+        TokenAuth { key }
+    }
+}
+
+impl<S, B> Transform<S> for TokenAuth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TokenAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TokenAuthMiddleware {
+            service,
+            key: self.key.clone(),
+        })
+    }
+}
+
+pub struct TokenAuthMiddleware<S> {
+    service: S,
+    key: String,
+}
+
+impl<S, B> Service for TokenAuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        match authenticate_token(&req, &self.key) {
+            Ok(_) => Either::Left(self.service.call(req)),
+            Err(e) => Either::Right(err(e.into())),
+        }
+    }
 }
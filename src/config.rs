@@ -0,0 +1,294 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use std::time::Duration;
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+// Settings are read from, in increasing precedence, the
+// defaults below, a TOML file (--config or HERD_CONFIG),
+// HERD_<NAME> environment variables and --<name> flags,
+// e.g. pool_size is HERD_POOL_SIZE and --pool-size.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: String,
+    // PEM files, when both are set the server only
+    // accepts TLS. SIGHUP reloads them.
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub pool_size: u32,
+    // env_logger style directives, e.g. "info,herd_api=debug"
+    pub log_filter: String,
+    // "text" or "json"
+    pub log_format: String,
+    pub heartbeat_interval_secs: u64,
+    // A device that doesn't answer pings for this
+    // long is disconnected
+    pub client_timeout_secs: u64,
+    pub topic_refresh_interval_secs: u64,
+    pub webhook_refresh_interval_secs: u64,
+    pub log_flush_interval_secs: u64,
+    pub log_prune_interval_secs: u64,
+    // Limits of newly created accounts
+    pub default_max_requests_per_minute: i32,
+    pub default_max_connections: i32,
+    pub default_log_retention_days: Option<i32>,
+    // Open GET /subscribe streams allowed per account
+    pub max_subscribers_per_account: usize,
+    // Time allowed for draining connections, webhooks
+    // and logs once a shutdown signal arrives
+    pub shutdown_timeout_secs: u64,
+    // Devices are told to reconnect after a random
+    // delay of up to this long, spreading the load on
+    // the next deployment
+    pub reconnect_spread_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "0.0.0.0:8080".to_string(),
+            tls_cert_path: None,
+            tls_key_path: None,
+            pool_size: 4,
+            log_filter: "info,actix_server=info,actix_web=info".to_string(),
+            log_format: "text".to_string(),
+            heartbeat_interval_secs: 5,
+            client_timeout_secs: 10,
+            topic_refresh_interval_secs: 60,
+            webhook_refresh_interval_secs: 60,
+            log_flush_interval_secs: 1,
+            log_prune_interval_secs: 10 * 60,
+            default_max_requests_per_minute: 60,
+            default_max_connections: 5,
+            default_log_retention_days: None,
+            max_subscribers_per_account: 10,
+            shutdown_timeout_secs: 30,
+            reconnect_spread_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+const ENV_PREFIX: &str = "HERD_";
+
+fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError(format!("invalid value {:?} for {}", value, name)))
+}
+
+impl Config {
+    pub fn load() -> Result<Config, ConfigError> {
+        let args: Vec<String> = env::args().skip(1).collect();
+        let flags = parse_flags(&args)?;
+
+        let path = flags.iter()
+            .find(|(name, _)| name == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| env::var(format!("{}CONFIG", ENV_PREFIX)).ok());
+
+        let mut config = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(&path)
+                    .map_err(|e| ConfigError(format!("unable to read {}: {}", path, e)))?;
+                toml::from_str(&contents)
+                    .map_err(|e| ConfigError(format!("invalid config file {}: {}", path, e)))?
+            },
+            None => Config::default(),
+        };
+
+        // The conventional variable and the names used before
+        // settings had a prefix still work, LOG_FILTER taking
+        // precedence over RUST_LOG as it did then
+        if let Ok(filter) = env::var("RUST_LOG") {
+            config.log_filter = filter;
+        }
+        for (name, setting) in LEGACY_ENV_VARS {
+            if let Ok(value) = env::var(name) {
+                config.set(setting, &value)?;
+            }
+        }
+        for name in SETTINGS {
+            if let Ok(value) = env::var(format!("{}{}", ENV_PREFIX, name.to_uppercase())) {
+                config.set(name, &value)?;
+            }
+        }
+        for (name, value) in flags.iter().filter(|(name, _)| name != "config") {
+            config.set(name, value)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        match name {
+            "bind" => self.bind = value.to_string(),
+            "tls_cert_path" => self.tls_cert_path = Some(value.to_string()),
+            "tls_key_path" => self.tls_key_path = Some(value.to_string()),
+            "pool_size" => self.pool_size = parse(name, value)?,
+            "log_filter" => self.log_filter = value.to_string(),
+            "log_format" => self.log_format = value.to_string(),
+            "heartbeat_interval_secs" => self.heartbeat_interval_secs = parse(name, value)?,
+            "client_timeout_secs" => self.client_timeout_secs = parse(name, value)?,
+            "topic_refresh_interval_secs" => self.topic_refresh_interval_secs = parse(name, value)?,
+            "webhook_refresh_interval_secs" => self.webhook_refresh_interval_secs = parse(name, value)?,
+            "log_flush_interval_secs" => self.log_flush_interval_secs = parse(name, value)?,
+            "log_prune_interval_secs" => self.log_prune_interval_secs = parse(name, value)?,
+            "default_max_requests_per_minute" => self.default_max_requests_per_minute = parse(name, value)?,
+            "default_max_connections" => self.default_max_connections = parse(name, value)?,
+            // An empty value means no retention limit
+            "default_log_retention_days" => self.default_log_retention_days = match value {
+                "" => None,
+                v => Some(parse(name, v)?),
+            },
+            "max_subscribers_per_account" => self.max_subscribers_per_account = parse(name, value)?,
+            "shutdown_timeout_secs" => self.shutdown_timeout_secs = parse(name, value)?,
+            "reconnect_spread_secs" => self.reconnect_spread_secs = parse(name, value)?,
+            _ => return Err(ConfigError(format!("unknown setting {}", name))),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError(reason.to_string()));
+
+        match self.bind.to_socket_addrs() {
+            Ok(mut addrs) => if addrs.next().is_none() {
+                return invalid("bind does not resolve to an address");
+            },
+            Err(e) => return Err(ConfigError(format!("invalid bind address {}: {}", self.bind, e))),
+        }
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            return invalid("tls_cert_path and tls_key_path must be set together");
+        }
+        if self.pool_size == 0 {
+            return invalid("pool_size must be greater than zero");
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log_filter) {
+            return Err(ConfigError(format!("invalid log_filter: {}", e)));
+        }
+        if self.log_format != "text" && self.log_format != "json" {
+            return invalid("log_format must be text or json");
+        }
+        let intervals = [
+            self.heartbeat_interval_secs,
+            self.client_timeout_secs,
+            self.topic_refresh_interval_secs,
+            self.webhook_refresh_interval_secs,
+            self.log_flush_interval_secs,
+            self.log_prune_interval_secs,
+            self.shutdown_timeout_secs,
+        ];
+        if intervals.iter().any(|i| *i == 0) {
+            return invalid("intervals must be greater than zero");
+        }
+        if self.client_timeout_secs <= self.heartbeat_interval_secs {
+            return invalid("client_timeout_secs must be greater than heartbeat_interval_secs");
+        }
+        if self.default_max_requests_per_minute <= 0 || self.default_max_connections <= 0 {
+            return invalid("default limits must be greater than zero");
+        }
+        if self.default_log_retention_days.map_or(false, |d| d <= 0) {
+            return invalid("default_log_retention_days must be greater than zero");
+        }
+        if self.max_subscribers_per_account == 0 {
+            return invalid("max_subscribers_per_account must be greater than zero");
+        }
+        Ok(())
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout_secs)
+    }
+
+    pub fn topic_refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.topic_refresh_interval_secs)
+    }
+
+    pub fn webhook_refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.webhook_refresh_interval_secs)
+    }
+
+    pub fn log_flush_interval(&self) -> Duration {
+        Duration::from_secs(self.log_flush_interval_secs)
+    }
+
+    pub fn log_prune_interval(&self) -> Duration {
+        Duration::from_secs(self.log_prune_interval_secs)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn reconnect_spread(&self) -> Duration {
+        Duration::from_secs(self.reconnect_spread_secs)
+    }
+}
+
+// Every setting that can be overridden from the
+// environment or command line
+const SETTINGS: &[&str] = &[
+    "bind",
+    "tls_cert_path",
+    "tls_key_path",
+    "pool_size",
+    "log_filter",
+    "log_format",
+    "heartbeat_interval_secs",
+    "client_timeout_secs",
+    "topic_refresh_interval_secs",
+    "webhook_refresh_interval_secs",
+    "log_flush_interval_secs",
+    "log_prune_interval_secs",
+    "default_max_requests_per_minute",
+    "default_max_connections",
+    "default_log_retention_days",
+    "max_subscribers_per_account",
+    "shutdown_timeout_secs",
+    "reconnect_spread_secs",
+];
+
+// Environment variables from before HERD_ prefixed ones,
+// overridden by the prefixed names
+const LEGACY_ENV_VARS: &[(&str, &str)] = &[
+    ("LOG_FILTER", "log_filter"),
+    ("LOG_FORMAT", "log_format"),
+];
+
+// --name value and --name=value pairs, with dashes
+// in names read as underscores
+fn parse_flags(args: &[String]) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            return Err(ConfigError(format!("unexpected argument {}", arg)));
+        }
+        let arg = &arg[2..];
+        let (name, value) = match arg.find('=') {
+            Some(i) => (arg[..i].to_string(), arg[i + 1..].to_string()),
+            None => match args.next() {
+                Some(value) => (arg.to_string(), value.clone()),
+                None => return Err(ConfigError(format!("missing value for --{}", arg))),
+            },
+        };
+        flags.push((name.replace('-', "_"), value));
+    }
+    Ok(flags)
+}
@@ -10,8 +10,10 @@ use diesel::pg::upsert::on_constraint;
 use std::env;
 use uuid::Uuid;
 use serde::{Serialize};
+use serde_json::Value;
 
 use crate::models;
+use crate::pagination::{Cursor, CursorPage, CursorPaginate};
 use crate::utils::{instant_to_seconds};
 
 pub type DbPool = Pool<ConnectionManager<PgConnection>>;
@@ -20,11 +22,11 @@ fn database_url() -> String {
     env::var("DATABASE_URL").expect("DATABASE_URL must be set")
 }
 
-pub fn init_pool() -> DbPool {
+pub fn init_pool(pool_size: u32) -> DbPool {
     let manager = ConnectionManager::<PgConnection>::new(database_url());
 
     let pool = Pool::builder()
-        .max_size(4)
+        .max_size(pool_size)
         .build(manager)
         .expect("db pool");
 
@@ -44,7 +46,7 @@ pub fn create_device_type<'a>(
     account_id: &'a str,
     description: Option<&'a str>,
     conn: &PgConnection,
-) -> Result<(), diesel::result::Error> {
+) -> Result<DeviceType, diesel::result::Error> {
     use crate::schema::device_types;
     let id = format!("devt_{}", generate_random_uuid());
     let new_device_type = models::NewDeviceType {
@@ -53,27 +55,37 @@ pub fn create_device_type<'a>(
         id: &id,
         description,
     };
-    diesel::insert_into(device_types::table).values(&new_device_type).execute(conn)?;
+    let device_type = diesel::insert_into(device_types::table)
+        .values(&new_device_type)
+        .get_result::<models::DeviceType>(conn)?;
 
-    Ok(())
+    Ok(DeviceType::from(device_type))
 }
 
+// Devices deleted through the API aren't registered
+// again, returns NotFound for them
 pub fn create_device<'a>(
     id: &'a str,
+    account_id: &'a str,
     device_type_id: &'a str,
     conn: &PgConnection,
 ) -> Result<(), diesel::result::Error> {
     use crate::schema::devices;
 
+    if device_deleted(account_id, id, conn)? {
+        return Err(diesel::result::Error::NotFound);
+    }
+
     let new_device = models::NewDevice {
         id,
+        account_id,
         device_type_id,
     };
 
     diesel::insert_into(devices::table)
         .values(&new_device)
-        // If a combination of device_id and
-        // device_type_id already exists, do nothing.
+        // If a combination of account_id and
+        // device_id already exists, do nothing.
         // We still want to error if a non existant
         // device_type_id is used
         .on_conflict(on_constraint("devices_pkey"))
@@ -82,38 +94,416 @@ pub fn create_device<'a>(
     Ok(())
 }
 
+#[derive(Debug, Serialize)]
+pub struct Device {
+    pub id: String,
+    pub device_type_id: String,
+    pub metadata: Value,
+    pub tags: Vec<String>,
+    // Filled in from the publisher, not stored
+    pub online: bool,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl From<models::Devices> for Device {
+    fn from(device: models::Devices) -> Self {
+        Device {
+            id: device.id,
+            device_type_id: device.device_type_id,
+            metadata: device.metadata,
+            tags: device.tags,
+            online: false,
+            created_at: instant_to_seconds(device.created_at),
+            updated_at: instant_to_seconds(device.updated_at),
+        }
+    }
+}
+
+pub fn get_devices<'a>(
+    account_id: &'a str,
+    device_type_id: Option<&'a str>,
+    tag: Option<&'a str>,
+    after: Option<&'a Cursor>,
+    limit: i64,
+    conn: &PgConnection,
+) -> Result<CursorPage<Device>, diesel::result::Error> {
+    use crate::schema::devices::dsl;
+
+    let mut query = dsl::devices
+        .filter(dsl::account_id.eq(account_id))
+        .into_boxed();
+
+    if let Some(device_type_id) = device_type_id {
+        query = query.filter(dsl::device_type_id.eq(device_type_id));
+    }
+    if let Some(tag) = tag {
+        query = query.filter(dsl::tags.contains(vec![tag.to_string()]));
+    }
+    if let Some(cursor) = after {
+        query = query.filter(
+            dsl::created_at.lt(cursor.created_at)
+                .or(dsl::created_at.eq(cursor.created_at).and(dsl::id.lt(cursor.id.as_str())))
+        );
+    }
+
+    let page = query
+        .order((dsl::created_at.desc(), dsl::id.desc()))
+        .paginate_after(limit)
+        .load_page::<models::Devices>(conn)?;
+
+    Ok(page.map(Device::from))
+}
+
+pub fn get_device<'a>(
+    account_id: &'a str,
+    device_id: &'a str,
+    conn: &PgConnection,
+) -> Result<Device, diesel::result::Error> {
+    use crate::schema::devices::dsl;
+
+    let device = dsl::devices
+        .filter(dsl::account_id.eq(account_id))
+        .filter(dsl::id.eq(device_id))
+        .first::<models::Devices>(conn)?;
+
+    Ok(Device::from(device))
+}
+
+pub fn update_device<'a>(
+    account_id: &'a str,
+    device_id: &'a str,
+    changes: &'a models::DeviceChangeset,
+    conn: &PgConnection,
+) -> Result<Device, diesel::result::Error> {
+    use crate::schema::devices::dsl;
+
+    let device = diesel::update(dsl::devices
+        .filter(dsl::account_id.eq(account_id))
+        .filter(dsl::id.eq(device_id)))
+        .set(changes)
+        .get_result::<models::Devices>(conn)?;
+
+    Ok(Device::from(device))
+}
+
+// Also remembers the device as deleted so it isn't
+// registered again when it reconnects
+pub fn delete_device<'a>(
+    account_id: &'a str,
+    device_id: &'a str,
+    conn: &PgConnection,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::devices::dsl;
+    use crate::schema::deleted_devices;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let deleted = diesel::delete(dsl::devices
+            .filter(dsl::account_id.eq(account_id))
+            .filter(dsl::id.eq(device_id)))
+            .execute(conn)?;
+        if deleted == 0 {
+            return Err(diesel::result::Error::NotFound);
+        }
+
+        diesel::insert_into(deleted_devices::table)
+            .values((
+                deleted_devices::dsl::account_id.eq(account_id),
+                deleted_devices::dsl::id.eq(device_id),
+            ))
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+pub fn device_deleted<'a>(
+    account_id: &'a str,
+    device_id: &'a str,
+    conn: &PgConnection,
+) -> Result<bool, diesel::result::Error> {
+    use crate::schema::deleted_devices::dsl;
+
+    select(exists(dsl::deleted_devices
+        .filter(dsl::account_id.eq(account_id))
+        .filter(dsl::id.eq(device_id))))
+        .get_result(conn)
+}
+
+// Lets a deleted device connect and be registered again
+pub fn restore_device<'a>(
+    account_id: &'a str,
+    device_id: &'a str,
+    conn: &PgConnection,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::deleted_devices::dsl;
+
+    let restored = diesel::delete(dsl::deleted_devices
+        .filter(dsl::account_id.eq(account_id))
+        .filter(dsl::id.eq(device_id)))
+        .execute(conn)?;
+
+    match restored {
+        0 => Err(diesel::result::Error::NotFound),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceSession {
+    pub id: String,
+    pub device_id: String,
+    pub device_type_id: String,
+    pub peer_address: Option<String>,
+    pub connected_at: u64,
+    // None while the session is still open
+    pub disconnected_at: Option<u64>,
+    pub close_reason: Option<String>,
+    pub messages_in: i64,
+    pub messages_out: i64,
+}
+
+impl From<models::DeviceSession> for DeviceSession {
+    fn from(session: models::DeviceSession) -> Self {
+        DeviceSession {
+            id: session.id,
+            device_id: session.device_id,
+            device_type_id: session.device_type_id,
+            peer_address: session.peer_address,
+            connected_at: instant_to_seconds(session.connected_at),
+            disconnected_at: session.disconnected_at.map(instant_to_seconds),
+            close_reason: session.close_reason,
+            messages_in: session.messages_in,
+            messages_out: session.messages_out,
+        }
+    }
+}
+
+pub fn create_device_session<'a>(
+    session_id: &'a str,
+    account_id: &'a str,
+    device_id: &'a str,
+    device_type_id: &'a str,
+    peer_address: Option<&'a str>,
+    conn: &PgConnection,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::device_sessions;
+
+    let new_session = models::NewDeviceSession {
+        id: session_id,
+        account_id,
+        device_id,
+        device_type_id,
+        peer_address,
+    };
+
+    diesel::insert_into(device_sessions::table)
+        .values(&new_session)
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn close_device_session<'a>(
+    session_id: &'a str,
+    disconnected_at: SystemTime,
+    close_reason: &'a str,
+    messages_in: i64,
+    messages_out: i64,
+    conn: &PgConnection,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::device_sessions::dsl;
+
+    diesel::update(dsl::device_sessions.filter(dsl::id.eq(session_id)))
+        .set((
+            dsl::disconnected_at.eq(disconnected_at),
+            dsl::close_reason.eq(close_reason),
+            dsl::messages_in.eq(messages_in),
+            dsl::messages_out.eq(messages_out),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+// Mark the sessions as still held by a running server
+pub fn touch_device_sessions<'a>(
+    session_ids: &'a [String],
+    conn: &PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::device_sessions::dsl;
+
+    diesel::update(dsl::device_sessions.filter(dsl::id.eq_any(session_ids)))
+        .set(dsl::last_seen_at.eq(SystemTime::now()))
+        .execute(conn)
+}
+
+// Close the sessions left open by a server that crashed
+// or was killed, i.e. not seen since stale_before. Other
+// running servers keep theirs. Returns how many were closed.
+pub fn close_open_device_sessions<'a>(
+    close_reason: &'a str,
+    stale_before: SystemTime,
+    conn: &PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::device_sessions::dsl;
+
+    let stale = dsl::device_sessions
+        .filter(dsl::disconnected_at.is_null())
+        .filter(dsl::last_seen_at.lt(stale_before));
+    diesel::update(stale)
+        .set((
+            // The last time the session was known to be open
+            dsl::disconnected_at.eq(dsl::last_seen_at.nullable()),
+            dsl::close_reason.eq(close_reason),
+        ))
+        .execute(conn)
+}
+
+pub fn get_device_sessions<'a>(
+    account_id: &'a str,
+    device_id: &'a str,
+    after: Option<&'a Cursor>,
+    limit: i64,
+    conn: &PgConnection,
+) -> Result<CursorPage<DeviceSession>, diesel::result::Error> {
+    use crate::schema::device_sessions::dsl;
+
+    let mut query = dsl::device_sessions
+        .filter(dsl::account_id.eq(account_id))
+        .filter(dsl::device_id.eq(device_id))
+        .into_boxed();
+
+    if let Some(cursor) = after {
+        query = query.filter(
+            dsl::connected_at.lt(cursor.created_at)
+                .or(dsl::connected_at.eq(cursor.created_at).and(dsl::id.lt(cursor.id.as_str())))
+        );
+    }
+
+    let page = query
+        .order((dsl::connected_at.desc(), dsl::id.desc()))
+        .paginate_after(limit)
+        .load_page::<models::DeviceSession>(conn)?;
+
+    Ok(page.map(DeviceSession::from))
+}
+
 #[derive(Debug, Serialize)]
 pub struct DeviceType {
     pub id: String,
     pub name: String,
     pub description: Option<String>,
     pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl From<models::DeviceType> for DeviceType {
+    fn from(device_type: models::DeviceType) -> Self {
+        DeviceType {
+            id: device_type.id,
+            name: device_type.name,
+            description: device_type.description,
+            created_at: instant_to_seconds(device_type.created_at),
+            updated_at: instant_to_seconds(device_type.updated_at),
+        }
+    }
 }
 
 pub fn get_device_types<'a>(
     account_id: &'a str,
+    after: Option<&'a Cursor>,
+    limit: i64,
     conn: &PgConnection,
-) -> Result<Vec<DeviceType>, diesel::result::Error>{
+) -> Result<CursorPage<DeviceType>, diesel::result::Error>{
     use crate::schema::device_types::dsl;
 
-    let result = dsl::device_types
+    let mut query = dsl::device_types
         .filter(dsl::account_id.eq(account_id))
-        .load::<models::DeviceType>(conn)
-        .expect("An error occurred");
-
-    let mut all_device_types = Vec::new();
-    for device_type in result {
-        all_device_types.push(
-            DeviceType {
-                id: device_type.id.clone(),
-                name: device_type.name,
-                description: device_type.description,
-                created_at: instant_to_seconds(device_type.created_at),
-            }
+        .into_boxed();
+
+    if let Some(cursor) = after {
+        query = query.filter(
+            dsl::created_at.lt(cursor.created_at)
+                .or(dsl::created_at.eq(cursor.created_at).and(dsl::id.lt(cursor.id.as_str())))
         );
     }
 
-    Ok(all_device_types)
+    let page = query
+        .order((dsl::created_at.desc(), dsl::id.desc()))
+        .paginate_after(limit)
+        .load_page::<models::DeviceType>(conn)?;
+
+    Ok(page.map(DeviceType::from))
+}
+
+pub fn get_device_type<'a>(
+    account_id: &'a str,
+    device_type_id: &'a str,
+    conn: &PgConnection,
+) -> Result<DeviceType, diesel::result::Error> {
+    use crate::schema::device_types::dsl;
+
+    let device_type = dsl::device_types
+        .filter(dsl::id.eq(device_type_id))
+        .filter(dsl::account_id.eq(account_id))
+        .first::<models::DeviceType>(conn)?;
+
+    Ok(DeviceType::from(device_type))
+}
+
+pub fn update_device_type<'a>(
+    account_id: &'a str,
+    device_type_id: &'a str,
+    changes: &'a models::DeviceTypeChangeset<'a>,
+    conn: &PgConnection,
+) -> Result<DeviceType, diesel::result::Error> {
+    use crate::schema::device_types::dsl;
+
+    let device_type = diesel::update(dsl::device_types
+        .filter(dsl::id.eq(device_type_id))
+        .filter(dsl::account_id.eq(account_id)))
+        .set(changes)
+        .get_result::<models::DeviceType>(conn)?;
+
+    Ok(DeviceType::from(device_type))
+}
+
+pub fn device_type_has_devices<'a>(
+    device_type_id: &'a str,
+    conn: &PgConnection,
+) -> Result<bool, diesel::result::Error> {
+    use crate::schema::devices::dsl;
+
+    select(exists(dsl::devices.filter(dsl::device_type_id.eq(device_type_id))))
+        .get_result::<bool>(conn)
+}
+
+// Deletes a device type along with all of its devices
+// Returns the ids of the devices deleted along with the
+// device type
+pub fn delete_device_type<'a>(
+    account_id: &'a str,
+    device_type_id: &'a str,
+    conn: &PgConnection,
+) -> Result<Vec<String>, diesel::result::Error> {
+    use crate::schema::device_types::dsl as device_types_dsl;
+    use crate::schema::devices::dsl as devices_dsl;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let owned = select(exists(device_types_dsl::device_types
+            .filter(device_types_dsl::id.eq(device_type_id))
+            .filter(device_types_dsl::account_id.eq(account_id))))
+            .get_result::<bool>(conn)?;
+        if !owned {
+            return Err(diesel::result::Error::NotFound);
+        }
+
+        let device_ids = diesel::delete(devices_dsl::devices.filter(devices_dsl::device_type_id.eq(device_type_id)))
+            .returning(devices_dsl::id)
+            .get_results::<String>(conn)?;
+        diesel::delete(device_types_dsl::device_types.filter(device_types_dsl::id.eq(device_type_id)))
+            .execute(conn)?;
+        Ok(device_ids)
+    })
 }
 
 pub fn device_type_relation_exists<'a>(
@@ -142,31 +532,59 @@ pub struct TopicType {
     pub updated_at: u64,
 }
 
+impl From<models::Topic> for TopicType {
+    fn from(topic: models::Topic) -> Self {
+        TopicType {
+            id: topic.id,
+            account_id: topic.account_id,
+            name: topic.name,
+            description: topic.description,
+            created_at: instant_to_seconds(topic.created_at),
+            updated_at: instant_to_seconds(topic.updated_at),
+        }
+    }
+}
+
 pub fn get_topics<'a>(
     account_id: &'a str,
+    after: Option<&'a Cursor>,
+    limit: i64,
     conn: &PgConnection,
-) -> Result<Vec<TopicType>, diesel::result::Error> {
+) -> Result<CursorPage<TopicType>, diesel::result::Error> {
     use crate::schema::topics::dsl;
 
-    let result = dsl::topics
+    let mut query = dsl::topics
         .filter(dsl::account_id.eq(account_id))
-        .load::<models::Topic>(conn)?;
+        .into_boxed();
 
-    let mut all_topics = Vec::new();
-    for topic in result {
-        all_topics.push(
-            TopicType {
-                id: topic.id,
-                account_id: topic.account_id,
-                name: topic.name,
-                description: topic.description,
-                created_at: instant_to_seconds(topic.created_at),
-                updated_at: instant_to_seconds(topic.updated_at),
-            }
+    if let Some(cursor) = after {
+        query = query.filter(
+            dsl::created_at.lt(cursor.created_at)
+                .or(dsl::created_at.eq(cursor.created_at).and(dsl::id.lt(cursor.id.as_str())))
         );
     }
 
-    Ok(all_topics)
+    let page = query
+        .order((dsl::created_at.desc(), dsl::id.desc()))
+        .paginate_after(limit)
+        .load_page::<models::Topic>(conn)?;
+
+    Ok(page.map(TopicType::from))
+}
+
+pub fn get_topic<'a>(
+    account_id: &'a str,
+    topic_id: &'a str,
+    conn: &PgConnection,
+) -> Result<TopicType, diesel::result::Error> {
+    use crate::schema::topics::dsl;
+
+    let topic = dsl::topics
+        .filter(dsl::id.eq(topic_id))
+        .filter(dsl::account_id.eq(account_id))
+        .first::<models::Topic>(conn)?;
+
+    Ok(TopicType::from(topic))
 }
 
 pub fn create_topic<'a>(
@@ -174,7 +592,7 @@ pub fn create_topic<'a>(
     account_id: &'a str,
     description: Option<&'a str>,
     conn: &PgConnection,
-) -> Result<(), diesel::result::Error> {
+) -> Result<TopicType, diesel::result::Error> {
     use crate::schema::topics;
 
     let id = format!("top_{}", generate_random_uuid());
@@ -185,10 +603,115 @@ pub fn create_topic<'a>(
         description,
     };
 
+    let topic = diesel::insert_into(topics::table)
+        .values(&new_topic)
+        .get_result::<models::Topic>(conn)?;
+    Ok(TopicType::from(topic))
+}
+
+pub fn update_topic<'a>(
+    account_id: &'a str,
+    topic_id: &'a str,
+    changes: &'a models::TopicChangeset<'a>,
+    conn: &PgConnection,
+) -> Result<TopicType, diesel::result::Error> {
+    use crate::schema::topics::dsl;
+
+    let topic = diesel::update(dsl::topics
+        .filter(dsl::id.eq(topic_id))
+        .filter(dsl::account_id.eq(account_id)))
+        .set(changes)
+        .get_result::<models::Topic>(conn)?;
+
+    Ok(TopicType::from(topic))
+}
+
+pub fn delete_topic<'a>(
+    account_id: &'a str,
+    topic_id: &'a str,
+    conn: &PgConnection,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::topics::dsl as topics_dsl;
+    use crate::schema::webhook_topics::dsl as webhook_topics_dsl;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        if !topic_relation_exists(account_id, topic_id, conn) {
+            return Err(diesel::result::Error::NotFound);
+        }
+
+        // Need to delete all webhook associations before
+        // deleting the topic
+        diesel::delete(webhook_topics_dsl::webhook_topics.filter(webhook_topics_dsl::topic_id.eq(topic_id)))
+            .execute(conn)?;
+        diesel::delete(topics_dsl::topics.filter(topics_dsl::id.eq(topic_id)))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+// Topic published to by the server whenever one of the
+// account's devices connects or disconnects
+pub const PRESENCE_TOPIC: &str = "$presence";
+
+// Topic names starting with $ are reserved for
+// topics managed by the server
+pub fn is_reserved_topic_name(name: &str) -> bool {
+    name.starts_with('$')
+}
+
+// Returns the id of the account's presence topic,
+// creating the topic the first time it's needed
+pub fn get_or_create_presence_topic<'a>(
+    account_id: &'a str,
+    conn: &PgConnection,
+) -> Result<String, diesel::result::Error> {
+    use crate::schema::topics;
+    use crate::schema::topics::dsl;
+
+    let id = format!("top_{}", generate_random_uuid());
+    let new_topic = models::NewTopic {
+        id: &id,
+        name: PRESENCE_TOPIC,
+        account_id,
+        description: Some("Device presence events"),
+    };
+
     diesel::insert_into(topics::table)
         .values(&new_topic)
+        .on_conflict((dsl::account_id, dsl::name))
+        .do_nothing()
         .execute(conn)?;
-    Ok(())
+
+    dsl::topics
+        .filter(dsl::account_id.eq(account_id))
+        .filter(dsl::name.eq(PRESENCE_TOPIC))
+        .select(dsl::id)
+        .first::<String>(conn)
+}
+
+// Topics can be referenced by either their id or their
+// name, returns the id of the account's topic if it exists
+pub fn resolve_topic_id<'a>(
+    account_id: &'a str,
+    topic: &'a str,
+    conn: &PgConnection,
+) -> Option<String> {
+    use crate::schema::topics::dsl;
+
+    let result = dsl::topics
+        .filter(dsl::account_id.eq(account_id))
+        .filter(dsl::id.eq(topic).or(dsl::name.eq(topic)))
+        .select(dsl::id)
+        .load::<String>(conn);
+
+    match result {
+        // Prefer an exact id match over a name match
+        Ok(ids) => match ids.iter().find(|id| id.as_str() == topic) {
+            Some(id) => Some(id.clone()),
+            None => ids.into_iter().next(),
+        },
+        Err(_) => None,
+    }
 }
 
 pub fn topic_relation_exists<'a>(
@@ -210,6 +733,7 @@ pub fn topic_relation_exists<'a>(
 pub struct TopicRelation {
     pub id: String,
     pub account_id: String,
+    pub name: String,
 }
 
 pub fn get_all_topic_relations<'a>(
@@ -218,15 +742,16 @@ pub fn get_all_topic_relations<'a>(
     use crate::schema::topics::dsl;
 
     let result = dsl::topics
-        .select((dsl::id, dsl::account_id))
-        .load::<(String, String)>(conn)?;
+        .select((dsl::id, dsl::account_id, dsl::name))
+        .load::<(String, String, String)>(conn)?;
 
     let mut relations = Vec::new();
     for item in result {
         relations.push(
             TopicRelation {
                 id: item.0,
-                account_id: item.1
+                account_id: item.1,
+                name: item.2,
             }
         );
     }
@@ -282,29 +807,36 @@ pub struct WebhookType {
 
 pub fn get_webhooks<'a>(
     account_id: &'a str,
+    after: Option<&'a Cursor>,
+    limit: i64,
     conn: &PgConnection,
-) -> Vec<WebhookType> {
+) -> Result<CursorPage<WebhookType>, diesel::result::Error> {
     use crate::schema::webhooks::dsl;
 
-    let result = dsl::webhooks
+    let mut query = dsl::webhooks
         .filter(dsl::account_id.eq(account_id))
-        .load::<models::Webhook>(conn)
-        .expect("An error occurred");
-
-    let mut all_webhooks = Vec::new();
-    for webhook in result {
-        all_webhooks.push(
-            WebhookType {
-                id: webhook.id,
-                account_id: webhook.account_id,
-                url: webhook.url,
-                created_at: instant_to_seconds(webhook.created_at),
-                updated_at: instant_to_seconds(webhook.updated_at),
-            }
+        .into_boxed();
+
+    if let Some(cursor) = after {
+        let id = cursor.int_id()?;
+        query = query.filter(
+            dsl::created_at.lt(cursor.created_at)
+                .or(dsl::created_at.eq(cursor.created_at).and(dsl::id.lt(id)))
         );
     }
 
-    all_webhooks
+    let page = query
+        .order((dsl::created_at.desc(), dsl::id.desc()))
+        .paginate_after(limit)
+        .load_page::<models::Webhook>(conn)?;
+
+    Ok(page.map(|webhook| WebhookType {
+        id: webhook.id,
+        account_id: webhook.account_id,
+        url: webhook.url,
+        created_at: instant_to_seconds(webhook.created_at),
+        updated_at: instant_to_seconds(webhook.updated_at),
+    }))
 }
 
 pub fn create_webhook_topic<'a>(
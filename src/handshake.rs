@@ -0,0 +1,114 @@
+use std::fmt;
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::StatusCode;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::publisher::Will;
+use crate::db;
+
+// Device ids are chosen by customers and end up as
+// keys in the publisher and rows in the devices table,
+// so keep them short and free of anything surprising
+const MAX_DEVICE_ID_LENGTH: usize = 64;
+
+#[derive(Debug)]
+pub struct Rejection {
+    status: StatusCode,
+    reason: String,
+}
+
+impl Rejection {
+    pub fn bad_request(reason: &str) -> Rejection {
+        Rejection { status: StatusCode::BAD_REQUEST, reason: reason.to_string() }
+    }
+
+    pub fn unauthorized(reason: &str) -> Rejection {
+        Rejection { status: StatusCode::UNAUTHORIZED, reason: reason.to_string() }
+    }
+
+    pub fn forbidden(reason: &str) -> Rejection {
+        Rejection { status: StatusCode::FORBIDDEN, reason: reason.to_string() }
+    }
+
+    pub fn not_found(reason: &str) -> Rejection {
+        Rejection { status: StatusCode::NOT_FOUND, reason: reason.to_string() }
+    }
+
+    pub fn unavailable(reason: &str) -> Rejection {
+        Rejection { status: StatusCode::SERVICE_UNAVAILABLE, reason: reason.to_string() }
+    }
+
+    // Log the rejected handshake and build the response
+    // sent back to the device
+    pub fn respond(&self, r: &HttpRequest) -> HttpResponse {
+        let peer = match r.peer_addr() {
+            Some(addr) => addr.to_string(),
+            None => "unknown".to_string(),
+        };
+        warn!(peer = %peer, status = self.status.as_u16(), reason = %self.reason, "Rejected websocket handshake");
+        HttpResponse::build(self.status).body(self.reason.clone())
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.status.as_u16(), self.reason)
+    }
+}
+
+pub fn header_value<'a>(r: &'a HttpRequest, name: &'static str) -> Result<&'a str, Rejection> {
+    let value = match r.headers().get(name) {
+        Some(v) => v,
+        None => return Err(Rejection::bad_request(&format!("Missing {} header", name))),
+    };
+    match value.to_str() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(Rejection::bad_request(&format!("{} header is not valid ASCII", name))),
+    }
+}
+
+pub fn validate_id<'a>(name: &'static str, id: &'a str) -> Result<(), Rejection> {
+    if id.is_empty() || id.len() > MAX_DEVICE_ID_LENGTH {
+        return Err(Rejection::bad_request(&format!(
+            "{} must be between 1 and {} characters",
+            name,
+            MAX_DEVICE_ID_LENGTH,
+        )));
+    }
+    let valid_chars = id.chars().all(|c| {
+        c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == ':'
+    });
+    if !valid_chars {
+        return Err(Rejection::bad_request(&format!(
+            "{} may only contain letters, digits, '-', '_', '.' and ':'",
+            name,
+        )));
+    }
+    Ok(())
+}
+
+// A device can declare a last will on connect with the
+// Will-Topic and Will-Payload (JSON) headers
+pub fn will<'a>(r: &'a HttpRequest) -> Result<Option<Will>, Rejection> {
+    let has_topic = r.headers().contains_key("Will-Topic");
+    let has_payload = r.headers().contains_key("Will-Payload");
+    if !has_topic && !has_payload {
+        return Ok(None);
+    }
+
+    let topic = header_value(r, "Will-Topic")?;
+    if db::is_reserved_topic_name(topic) {
+        return Err(Rejection::forbidden("Will-Topic can't be a reserved topic"));
+    }
+    let payload = header_value(r, "Will-Payload")?;
+    let data: Value = match serde_json::from_str(payload) {
+        Ok(d) => d,
+        Err(_) => return Err(Rejection::bad_request("Will-Payload header is not valid JSON")),
+    };
+
+    Ok(Some(Will {
+        topic: topic.to_string(),
+        data,
+    }))
+}
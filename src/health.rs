@@ -0,0 +1,68 @@
+use std::time::Duration;
+use actix::prelude::*;
+use diesel::prelude::*;
+use diesel_migrations::any_pending_migrations;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::db::DbPool;
+use crate::metrics::MailboxProbe;
+
+// How long a readiness check waits on the database or
+// an actor before reporting it as not ready
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Map<String, Value>,
+}
+
+impl Readiness {
+    pub fn new() -> Readiness {
+        Readiness { ready: true, checks: Map::new() }
+    }
+
+    pub fn add(&mut self, name: &str, result: Result<(), String>) {
+        let check = match result {
+            Ok(_) => json!({ "ok": true }),
+            Err(e) => {
+                self.ready = false;
+                json!({ "ok": false, "error": e })
+            },
+        };
+        self.checks.insert(name.to_string(), check);
+    }
+}
+
+// Blocks, run on the blocking pool
+pub fn check_database(pool: &DbPool) -> Result<(), String> {
+    let conn = pool.get_timeout(CHECK_TIMEOUT).map_err(|e| e.to_string())?;
+    diesel::sql_query("SELECT 1")
+        .execute(&conn)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Blocks, run on the blocking pool
+pub fn check_migrations(pool: &DbPool) -> Result<(), String> {
+    let conn = pool.get_timeout(CHECK_TIMEOUT).map_err(|e| e.to_string())?;
+    match any_pending_migrations(&conn) {
+        Ok(false) => Ok(()),
+        Ok(true) => Err("pending migrations".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Round trip through the actor's mailbox, fails if the
+// actor is gone or too busy to answer in time
+pub async fn check_actor<A>(addr: &Addr<A>) -> Result<(), String>
+where
+    A: Actor + Handler<MailboxProbe>,
+    A::Context: actix::dev::ToEnvelope<A, MailboxProbe>,
+{
+    addr.send(MailboxProbe)
+        .timeout(CHECK_TIMEOUT)
+        .await
+        .map_err(|e| e.to_string())
+}
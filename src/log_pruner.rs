@@ -0,0 +1,104 @@
+use actix;
+use actix::prelude::*;
+use std::time::{Duration};
+use tracing::{error, info, warn};
+
+use crate::account;
+use crate::db::DbPool;
+use crate::logging;
+
+// Deleting in small batches keeps each statement short so
+// it doesn't hold locks against the inserts of live logs
+const PRUNE_BATCH_SIZE: i64 = 5000;
+
+// Deletes logs past each account's retention. Runs in
+// its own arbiter since pruning blocks on the database.
+pub struct LogPruner {
+    pool: DbPool,
+    interval: Duration,
+}
+
+impl Actor for LogPruner {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        info!("Log pruner started");
+        self.prune();
+        ctx.run_interval(self.interval, |act, _ctx| {
+            act.prune();
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        info!("Log pruner stopped");
+    }
+}
+
+impl LogPruner {
+    pub fn initialize(pool: DbPool, interval: Duration) -> LogPruner {
+        LogPruner { pool, interval }
+    }
+
+    fn prune(&self) {
+        let conn = match self.pool.get() {
+            Ok(c) => c,
+            Err(e) => {
+                error!(error = ?e, "Error getting db connection");
+                return;
+            },
+        };
+        let retentions = match account::get_log_retentions(&conn) {
+            Ok(r) => r,
+            Err(e) => {
+                error!(error = ?e, "Error getting log retentions");
+                return;
+            },
+        };
+
+        let mut total = 0;
+        for (account_id, retention) in retentions {
+            // The API only stores positive limits, anything
+            // else was written by hand and is left alone
+            let positive = |limit: Option<i32>| limit.map_or(true, |l| l > 0);
+            if !positive(retention.days) || !positive(retention.max_rows) {
+                warn!(account_id = %account_id, "Invalid log retention, skipping account");
+                continue;
+            }
+            if let Some(days) = retention.days {
+                total += prune_batches(|| {
+                    logging::prune_expired_logs(&account_id, days, PRUNE_BATCH_SIZE, &conn)
+                });
+            }
+            if let Some(max_rows) = retention.max_rows {
+                total += prune_batches(|| {
+                    logging::prune_excess_logs(&account_id, max_rows, PRUNE_BATCH_SIZE, &conn)
+                });
+            }
+        }
+        if total > 0 {
+            info!(count = total, "Pruned logs");
+        }
+    }
+}
+
+// Run a batch delete until a batch comes back short
+fn prune_batches<F>(delete_batch: F) -> usize
+where
+    F: Fn() -> Result<usize, diesel::result::Error>,
+{
+    let mut total = 0;
+    loop {
+        match delete_batch() {
+            Ok(deleted) => {
+                total += deleted;
+                if (deleted as i64) < PRUNE_BATCH_SIZE {
+                    return total;
+                }
+            },
+            Err(e) => {
+                error!(error = ?e, "Error pruning logs");
+                return total;
+            },
+        }
+    }
+}
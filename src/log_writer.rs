@@ -0,0 +1,311 @@
+use actix;
+use actix::prelude::*;
+use std::time::{Duration, SystemTime};
+use std::collections::HashSet;
+use actix_web::web::Bytes;
+use diesel::prelude::*;
+use futures::channel::mpsc;
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+
+use crate::db;
+use crate::db::DbPool;
+use crate::logging;
+use crate::models;
+use crate::sse;
+use crate::utils::{instant_to_seconds};
+
+// Entries waiting in the mailbox, once full new entries
+// are dropped rather than slowing down the publisher
+const LOG_QUEUE_SIZE: usize = 10000;
+// Largest multi-row insert
+const LOG_BATCH_SIZE: usize = 500;
+// How often the open sessions' last_seen_at is bumped
+const SESSION_TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+// Open sessions not touched for this long belong to a
+// server that is gone
+pub const SESSION_STALE_AFTER: Duration = Duration::from_secs(3 * 60);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct LogEntry {
+    pub account_id: String,
+    pub level: String,
+    pub data: Value,
+}
+
+// Device session history is written here too, keeping
+// it off the websocket actors
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SessionStarted {
+    pub session_id: String,
+    pub account_id: String,
+    pub device_id: String,
+    pub device_type_id: String,
+    pub peer_address: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SessionEnded {
+    pub session_id: String,
+    pub disconnected_at: SystemTime,
+    pub close_reason: &'static str,
+    pub messages_in: i64,
+    pub messages_out: i64,
+}
+
+// Write everything buffered now rather than on the
+// next interval and end every tail, sent while draining
+// on shutdown
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseTails();
+
+// Start sending an account's new logs, as server-sent
+// events, to sender
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TailLogs {
+    pub account_id: String,
+    pub level: Option<String>,
+    pub device_id: Option<String>,
+    pub sender: mpsc::Sender<Bytes>,
+}
+
+struct LogTail {
+    account_id: String,
+    level: Option<String>,
+    device_id: Option<String>,
+}
+
+impl LogTail {
+    fn matches(&self, entry: &LogEntry) -> bool {
+        if self.account_id != entry.account_id {
+            return false;
+        }
+        if let Some(level) = &self.level {
+            if level != &entry.level {
+                return false;
+            }
+        }
+        if let Some(device_id) = &self.device_id {
+            // Same places filtered_logs looks
+            let device = entry.data.get("device_id")
+                .or_else(|| entry.data.get("sender").and_then(|s| s.get("device_id")));
+            if device.and_then(|d| d.as_str()) != Some(device_id.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Buffers logs and writes them with multi-row inserts.
+// Runs in its own arbiter since writes block on the database.
+pub struct LogWriter {
+    pool: DbPool,
+    buffer: Vec<LogEntry>,
+    flush_interval: Duration,
+    tails: sse::Clients<LogTail>,
+    // Ids of the sessions open on this server
+    open_sessions: HashSet<String>,
+}
+
+impl Actor for LogWriter {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        info!("Log writer started");
+        ctx.set_mailbox_capacity(LOG_QUEUE_SIZE);
+        ctx.run_interval(self.flush_interval, |act, _ctx| {
+            act.flush();
+            let dropped = logging::take_dropped_logs();
+            if dropped > 0 {
+                warn!(count = dropped, "Log queue full, dropped logs");
+            }
+        });
+        ctx.run_interval(sse::KEEP_ALIVE_INTERVAL, |act, _ctx| {
+            act.tails.keep_alive();
+        });
+        ctx.run_interval(SESSION_TOUCH_INTERVAL, |act, _ctx| {
+            act.touch_sessions();
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        self.flush();
+        info!("Log writer stopped");
+    }
+}
+
+impl LogWriter {
+    pub fn initialize(pool: DbPool, flush_interval: Duration) -> LogWriter {
+        LogWriter {
+            pool,
+            buffer: Vec::with_capacity(LOG_BATCH_SIZE),
+            flush_interval,
+            tails: sse::Clients::default(),
+            open_sessions: HashSet::new(),
+        }
+    }
+
+    fn tail(&mut self, entry: &LogEntry) {
+        if self.tails.is_empty() {
+            return;
+        }
+        let event = format!("data: {}\n\n", json!({
+            "account_id": entry.account_id,
+            "level": entry.level,
+            "data": entry.data,
+            "created_at": instant_to_seconds(SystemTime::now()),
+        }));
+        self.tails.send(|tail| tail.matches(entry), &event);
+    }
+
+    fn touch_sessions(&mut self) {
+        if self.open_sessions.is_empty() {
+            return;
+        }
+        let conn = match self.pool.get() {
+            Ok(c) => c,
+            Err(e) => {
+                error!(error = ?e, "Error getting db connection");
+                return;
+            },
+        };
+        let session_ids: Vec<String> = self.open_sessions.iter().cloned().collect();
+        if let Err(e) = db::touch_device_sessions(&session_ids, &conn) {
+            error!(error = ?e, count = session_ids.len(), "Error touching sessions");
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let entries: Vec<LogEntry> = self.buffer.drain(..).collect();
+        let conn = match self.pool.get() {
+            Ok(c) => c,
+            Err(e) => {
+                error!(error = ?e, count = entries.len(), "Error getting db connection, dropped logs");
+                logging::count_dropped_logs(entries.len() as u64);
+                return;
+            },
+        };
+
+        use crate::schema::logs;
+
+        let new_logs: Vec<models::NewLog> = entries
+            .iter()
+            .map(|entry| models::NewLog {
+                account_id: &entry.account_id,
+                level: &entry.level,
+                data: Some(entry.data.clone()),
+            })
+            .collect();
+
+        let result = diesel::insert_into(logs::table)
+            .values(&new_logs)
+            .execute(&conn);
+        if let Err(e) = result {
+            error!(error = ?e, count = new_logs.len(), "Error writing logs");
+            logging::count_dropped_logs(new_logs.len() as u64);
+            return;
+        }
+
+        // Tails only see logs that were written, so they
+        // match what GET /logs returns
+        for entry in entries.iter() {
+            self.tail(entry);
+        }
+    }
+}
+
+impl Handler<LogEntry> for LogWriter {
+    type Result = ();
+
+    fn handle(&mut self, msg: LogEntry, _ctx: &mut Context<Self>) -> Self::Result {
+        self.buffer.push(msg);
+        if self.buffer.len() >= LOG_BATCH_SIZE {
+            self.flush();
+        }
+    }
+}
+
+impl Handler<SessionStarted> for LogWriter {
+    type Result = ();
+
+    fn handle(&mut self, msg: SessionStarted, _ctx: &mut Context<Self>) -> Self::Result {
+        let conn = match self.pool.get() {
+            Ok(c) => c,
+            Err(e) => {
+                error!(error = ?e, "Error getting db connection");
+                return;
+            },
+        };
+        let result = db::create_device_session(
+            &msg.session_id,
+            &msg.account_id,
+            &msg.device_id,
+            &msg.device_type_id,
+            msg.peer_address.as_deref(),
+            &conn,
+        );
+        match result {
+            Ok(_) => {
+                self.open_sessions.insert(msg.session_id);
+            },
+            Err(e) => error!(error = ?e, session_id = %msg.session_id, "Error recording session"),
+        }
+    }
+}
+
+impl Handler<SessionEnded> for LogWriter {
+    type Result = ();
+
+    fn handle(&mut self, msg: SessionEnded, _ctx: &mut Context<Self>) -> Self::Result {
+        self.open_sessions.remove(&msg.session_id);
+        let conn = match self.pool.get() {
+            Ok(c) => c,
+            Err(e) => {
+                error!(error = ?e, "Error getting db connection");
+                return;
+            },
+        };
+        let result = db::close_device_session(
+            &msg.session_id,
+            msg.disconnected_at,
+            msg.close_reason,
+            msg.messages_in,
+            msg.messages_out,
+            &conn,
+        );
+        if let Err(e) = result {
+            error!(error = ?e, session_id = %msg.session_id, "Error recording session");
+        }
+    }
+}
+
+impl Handler<CloseTails> for LogWriter {
+    type Result = ();
+
+    fn handle(&mut self, _msg: CloseTails, _ctx: &mut Context<Self>) -> Self::Result {
+        // Tails get the last logs before they end
+        self.flush();
+        self.tails.close(|_| None);
+    }
+}
+
+impl Handler<TailLogs> for LogWriter {
+    type Result = ();
+
+    fn handle(&mut self, msg: TailLogs, _ctx: &mut Context<Self>) -> Self::Result {
+        self.tails.add(LogTail {
+            account_id: msg.account_id,
+            level: msg.level,
+            device_id: msg.device_id,
+        }, msg.sender);
+    }
+}
@@ -1,12 +1,18 @@
 use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use actix::prelude::*;
+use std::time::{Duration, SystemTime};
 use diesel::prelude::*;
-use diesel::pg::PgConnection;
+use diesel::dsl::sql;
+use diesel::pg::{Pg, PgConnection};
+use diesel::sql_types::{Bool, Jsonb};
 use serde::Serialize;
-use serde_json::{Value};
+use serde_json::{json, Value};
 
-use crate::pagination::Paginate;
+use crate::pagination::{Cursor, CursorPage, CursorPaginate, Paginate};
 use crate::models;
-use crate::db::DbPool;
+use crate::log_writer::{LogEntry, LogWriter};
+use crate::metrics;
 use crate::utils::{instant_to_seconds};
 
 pub enum LogLevel {
@@ -23,43 +29,34 @@ impl fmt::Display for LogLevel {
     }
 }
 
+// Logs dropped since the log writer last reported them
+static DROPPED_LOGS: AtomicU64 = AtomicU64::new(0);
+
+// Queue a log for the log writer. Never blocks, if the
+// writer is falling behind the log is dropped and counted.
 pub fn log<'a>(
     account_id: &'a str,
     log_level: LogLevel,
     data: Value,
-    pool: &DbPool,
+    log_writer: &Addr<LogWriter>,
 ) -> () {
-    match pool.get() {
-        Ok(conn) => {
-            let _ = insert_log(
-                account_id,
-                log_level,
-                Some(data),
-                &conn
-            );
-        },
-        Err(e) => println!("Error getting db connection: {:?}", e),
-    };
+    let result = log_writer.try_send(LogEntry {
+        account_id: account_id.to_string(),
+        level: log_level.to_string(),
+        data,
+    });
+    if result.is_err() {
+        count_dropped_logs(1);
+    }
 }
 
-pub fn insert_log<'a>(
-    account_id: &'a str,
-    log_level: LogLevel,
-    data: Option<Value>,
-    conn: &PgConnection
-) -> Result<(), diesel::result::Error> {
-    use crate::schema::logs;
-
-    let new_log = models::NewLog {
-        account_id,
-        level: &log_level.to_string(),
-        data,
-    };
+pub fn count_dropped_logs(count: u64) {
+    DROPPED_LOGS.fetch_add(count, Ordering::Relaxed);
+    metrics::LOGS_DROPPED.inc_by(count as i64);
+}
 
-    diesel::insert_into(logs::table)
-        .values(&new_log)
-        .execute(conn)?;
-    Ok(())
+pub fn take_dropped_logs() -> u64 {
+    DROPPED_LOGS.swap(0, Ordering::Relaxed)
 }
 
 #[derive(Debug, Serialize)]
@@ -76,8 +73,79 @@ pub struct Paginated<T> {
     total_pages: i64
 }
 
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    pub level: Option<String>,
+    // device_id and device_type_id are matched both at the
+    // top level of data (connection logs) and in data.sender
+    // (message logs)
+    pub device_id: Option<String>,
+    pub device_type_id: Option<String>,
+    // The "message" field of data, e.g. "connected"
+    pub message: Option<String>,
+    // Seconds since unix epoch, inclusive
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    // Only logs whose data contains this document
+    pub contains: Option<Value>,
+}
+
+type LogExpression = Box<dyn BoxableExpression<crate::schema::logs::table, Pg, SqlType = Bool>>;
+
+// JSONB containment on the data column, backed
+// by the logs_data_index GIN index
+fn data_contains(document: Value) -> LogExpression {
+    Box::new(sql::<Bool>("data @> ").bind::<Jsonb, _>(document))
+}
+
+fn seconds_to_time(seconds: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+pub fn filtered_logs<'a>(
+    account_id: &'a str,
+    filter: &'a LogFilter,
+) -> crate::schema::logs::BoxedQuery<'a, Pg> {
+    use crate::schema::logs::dsl;
+
+    let mut query = dsl::logs
+        .filter(dsl::account_id.eq(account_id))
+        .into_boxed();
+
+    if let Some(level) = &filter.level {
+        query = query.filter(dsl::level.eq(level.as_str()));
+    }
+    if let Some(device_id) = &filter.device_id {
+        query = query.filter(
+            data_contains(json!({ "device_id": device_id }))
+                .or(data_contains(json!({ "sender": { "device_id": device_id } })))
+        );
+    }
+    if let Some(device_type_id) = &filter.device_type_id {
+        query = query.filter(
+            data_contains(json!({ "device_type_id": device_type_id }))
+                .or(data_contains(json!({ "sender": { "device_type_id": device_type_id } })))
+        );
+    }
+    if let Some(message) = &filter.message {
+        query = query.filter(data_contains(json!({ "message": message })));
+    }
+    if let Some(from) = filter.from {
+        query = query.filter(dsl::created_at.ge(seconds_to_time(from)));
+    }
+    if let Some(to) = filter.to {
+        query = query.filter(dsl::created_at.le(seconds_to_time(to)));
+    }
+    if let Some(contains) = &filter.contains {
+        query = query.filter(data_contains(contains.clone()));
+    }
+
+    query
+}
+
 pub fn paginated_logs<'a>(
     account_id: &'a str,
+    filter: &'a LogFilter,
     page_number: Option<u32>,
     page_size: Option<u32>,
     conn: &PgConnection,
@@ -93,9 +161,8 @@ pub fn paginated_logs<'a>(
         None => 10,
     };
 
-    let (results, total_pages) = logs::table
+    let (results, total_pages) = filtered_logs(account_id, filter)
         .order(logs::dsl::created_at.desc())
-        .filter(logs::dsl::account_id.eq(account_id))
         .paginate(page as i64)
         .per_page(limit as i64)
         .load_and_count_pages::<models::Log>(conn)?;
@@ -118,3 +185,177 @@ pub fn paginated_logs<'a>(
         total_pages,
     })
 }
+
+// Newest logs first, starting after the given cursor
+pub fn cursor_logs<'a>(
+    account_id: &'a str,
+    filter: &'a LogFilter,
+    after: Option<&'a Cursor>,
+    limit: i64,
+    conn: &PgConnection,
+) -> Result<CursorPage<LogType>, diesel::result::Error> {
+    use crate::schema::logs::dsl;
+
+    let mut query = filtered_logs(account_id, filter);
+
+    if let Some(cursor) = after {
+        let id = cursor.int_id()?;
+        query = query.filter(
+            dsl::created_at.lt(cursor.created_at)
+                .or(dsl::created_at.eq(cursor.created_at).and(dsl::id.lt(id)))
+        );
+    }
+
+    let page = query
+        .order((dsl::created_at.desc(), dsl::id.desc()))
+        .paginate_after(limit)
+        .load_page::<models::Log>(conn)?;
+
+    Ok(page.map(|log| LogType {
+        account_id: log.account_id,
+        level: log.level,
+        data: log.data,
+        created_at: instant_to_seconds(log.created_at),
+    }))
+}
+
+// Delete up to batch_size of the account's logs older than
+// days, returns how many were deleted. days must be positive.
+pub fn prune_expired_logs<'a>(
+    account_id: &'a str,
+    days: i32,
+    batch_size: i64,
+    conn: &PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::logs::dsl;
+
+    if days <= 0 {
+        return Err(diesel::result::Error::QueryBuilderError("retention days must be positive".into()));
+    }
+    let retention = Duration::from_secs(days as u64 * 24 * 60 * 60);
+    // Nothing can be older than a cutoff before the epoch
+    let cutoff = match SystemTime::now().checked_sub(retention) {
+        Some(c) => c,
+        None => return Ok(0),
+    };
+    // diesel can't delete with a limited subquery, so the
+    // ids are selected first
+    conn.transaction(|| {
+        let ids: Vec<i32> = dsl::logs
+            .filter(dsl::account_id.eq(account_id))
+            .filter(dsl::created_at.lt(cutoff))
+            .select(dsl::id)
+            .limit(batch_size)
+            .load(conn)?;
+        diesel::delete(dsl::logs.filter(dsl::id.eq_any(ids))).execute(conn)
+    })
+}
+
+// Delete up to batch_size of the account's logs beyond
+// the newest max_rows, returns how many were deleted.
+// max_rows must be positive.
+pub fn prune_excess_logs<'a>(
+    account_id: &'a str,
+    max_rows: i32,
+    batch_size: i64,
+    conn: &PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::logs::dsl;
+
+    if max_rows <= 0 {
+        return Err(diesel::result::Error::QueryBuilderError("retention max_rows must be positive".into()));
+    }
+
+    conn.transaction(|| {
+        let ids: Vec<i32> = dsl::logs
+            .filter(dsl::account_id.eq(account_id))
+            .order((dsl::created_at.desc(), dsl::id.desc()))
+            .select(dsl::id)
+            .offset(max_rows as i64)
+            .limit(batch_size)
+            .load(conn)?;
+        diesel::delete(dsl::logs.filter(dsl::id.eq_any(ids))).execute(conn)
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    // One JSON log per line
+    Ndjson,
+    // created_at, level, data with data as a JSON string
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Option<ExportFormat> {
+        match format {
+            "ndjson" => Some(ExportFormat::Ndjson),
+            "csv" => Some(ExportFormat::Csv),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Csv => "csv",
+        }
+    }
+
+    // Written once before the first log
+    pub fn header(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "",
+            ExportFormat::Csv => "created_at,level,data\n",
+        }
+    }
+
+    // Written last, so clients can tell a complete export
+    // from a response cut short. error is None when every
+    // log was written.
+    pub fn trailer(&self, count: usize, error: Option<&str>) -> String {
+        match (self, error) {
+            (ExportFormat::Ndjson, None) => format!("{}\n", json!({ "export": "complete", "count": count })),
+            (ExportFormat::Ndjson, Some(e)) => format!("{}\n", json!({ "export": "failed", "count": count, "error": e })),
+            (ExportFormat::Csv, None) => format!("#export_complete,{}\n", count),
+            (ExportFormat::Csv, Some(e)) => format!("#export_failed,{},{}\n", count, csv_field(e)),
+        }
+    }
+
+    pub fn encode(&self, logs: &[LogType]) -> String {
+        let mut out = String::new();
+        for log in logs {
+            match self {
+                ExportFormat::Ndjson => {
+                    out.push_str(&serde_json::to_string(log).unwrap_or_default());
+                },
+                ExportFormat::Csv => {
+                    let data = match &log.data {
+                        Some(d) => d.to_string(),
+                        None => String::new(),
+                    };
+                    out.push_str(&format!(
+                        "{},{},{}",
+                        log.created_at,
+                        csv_field(&log.level),
+                        csv_field(&data),
+                    ));
+                },
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+// Quote a CSV field, doubling any quotes inside it
+fn csv_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
@@ -4,22 +4,27 @@ extern crate ctrlc;
 extern crate ring;
 extern crate data_encoding;
 extern crate rand;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate prometheus;
 use std::any::Any;
 use std::sync::Arc;
 use std::collections::{HashSet};
 use std::env;
+use std::time::SystemTime;
 
-use serde_json::Value;
+use serde_json::{json, Value};
 use actix;
 use actix::prelude::*;
-use actix_web::dev::ServiceRequest;
-use actix_web_httpauth::extractors::bearer::BearerAuth;
 use actix_web_httpauth::extractors::basic::BasicAuth;
-use actix_web_httpauth::middleware::HttpAuthentication;
-use actix_web::{middleware, web, App, error, Error, HttpRequest, HttpResponse, HttpServer};
+use actix_web::{middleware, web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
-use serde::{Deserialize};
-use futures::executor;
+use serde::{Deserialize, Serialize};
+use actix_service::Service;
+use tracing::{error, info, warn, Span};
+use tracing_futures::Instrument;
+use futures::stream;
 
 mod auth;
 mod db;
@@ -31,6 +36,17 @@ mod utils;
 mod logging;
 mod pagination;
 mod account;
+mod handshake;
+mod shadow;
+mod log_pruner;
+mod log_writer;
+mod metrics;
+mod telemetry;
+mod health;
+mod config;
+mod tls;
+mod shutdown;
+mod sse;
 
 pub mod schema;
 pub mod models;
@@ -57,58 +73,252 @@ fn return_result_body<T: serde::Serialize>(result: Result<T, diesel::result::Err
     }
 }
 
+// Map a database error to the response returned to the client
+fn db_error_response(error: diesel::result::Error) -> HttpResponse {
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+    match error {
+        DieselError::NotFound => HttpResponse::NotFound().finish(),
+        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) =>
+            HttpResponse::Conflict().body(info.message().to_string()),
+        DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, info) =>
+            HttpResponse::Conflict().body(info.message().to_string()),
+        DieselError::QueryBuilderError(e) => HttpResponse::BadRequest().body(e.to_string()),
+        _ => HttpResponse::BadRequest().finish(),
+    }
+}
+
+const DEFAULT_PAGE_SIZE: u32 = 100;
+const MAX_PAGE_SIZE: u32 = 1000;
+
+// List endpoints are paged with an opaque cursor, pass the
+// next_cursor of a page to get the one after it
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    cursor: Option<String>,
+    limit: Option<u32>,
+}
+
+fn page_params(
+    cursor: &Option<String>,
+    limit: Option<u32>,
+    default_limit: u32,
+) -> Result<(Option<pagination::Cursor>, i64), HttpResponse> {
+    let limit = limit.unwrap_or(default_limit);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(HttpResponse::BadRequest().body(format!("limit must be between 1 and {}", MAX_PAGE_SIZE)));
+    }
+    let cursor = match cursor {
+        Some(c) => match pagination::Cursor::decode(c) {
+            Some(c) => Some(c),
+            None => return Err(HttpResponse::BadRequest().body("invalid cursor")),
+        },
+        None => None,
+    };
+    Ok((cursor, limit as i64))
+}
+
+// Lists answer with a {items, next_cursor} page when the
+// client passes a cursor or limit. Clients from before cursor
+// pagination pass neither and still get every item as an array.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Listing<T> {
+    All(Vec<T>),
+    Page(pagination::CursorPage<T>),
+}
+
+impl<T> Listing<T> {
+    fn items_mut(&mut self) -> &mut Vec<T> {
+        match self {
+            Listing::All(items) => items,
+            Listing::Page(page) => &mut page.items,
+        }
+    }
+}
+
+fn load_listing<T, F>(
+    cursor: &Option<String>,
+    limit: Option<u32>,
+    mut load: F,
+) -> Result<Listing<T>, HttpResponse>
+where
+    F: FnMut(Option<&pagination::Cursor>, i64) -> Result<pagination::CursorPage<T>, diesel::result::Error>,
+{
+    if cursor.is_none() && limit.is_none() {
+        let mut items = Vec::new();
+        let mut after: Option<pagination::Cursor> = None;
+        loop {
+            let page = load(after.as_ref(), MAX_PAGE_SIZE as i64).map_err(db_error_response)?;
+            items.extend(page.items);
+            after = match page.next_cursor.as_deref().and_then(pagination::Cursor::decode) {
+                Some(c) => Some(c),
+                None => return Ok(Listing::All(items)),
+            };
+        }
+    }
+
+    let (cursor, limit) = page_params(cursor, limit, DEFAULT_PAGE_SIZE)?;
+    load(cursor.as_ref(), limit)
+        .map(Listing::Page)
+        .map_err(db_error_response)
+}
+
 async fn health_check() -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
-// TODO: better error handling
-async fn ws_index(
-    auth: BasicAuth,
+// The process is up and serving requests
+async fn liveness() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+// Whether this instance should receive traffic, 503 with the
+// failing checks otherwise
+async fn readiness(
     pool: web::Data<db::DbPool>,
-    r: HttpRequest,
-    stream: web::Payload,
     publish: web::Data<Addr<publisher::Publisher>>,
-    api_cipher_key: web::Data<ApiCipherKey>,
-) -> Result<HttpResponse, Error> {
-    // Validate websocket connection
-    let conn = pool.get().expect("Failed to get a db connection");
-    let account_id = auth::authenticate_connection(auth, &api_cipher_key.0, &conn)?;
+    webhook_publish: web::Data<Addr<webhook_publisher::WebhookPublisher>>,
+    draining: web::Data<shutdown::Draining>,
+) -> HttpResponse {
+    let mut readiness = health::Readiness::new();
 
-    let device_id: &str = r.headers().get("Device-Id").unwrap().to_str().unwrap();
-    let device_type_id: &str = r.headers().get("Device-Type-Id").unwrap().to_str().unwrap();
+    readiness.add("accepting_connections", match draining.is_draining() {
+        true => Err("server is shutting down".to_string()),
+        false => Ok(()),
+    });
 
-    let relation_exists = db::device_type_relation_exists(
-        &account_id,
-        device_type_id,
-        &conn,
-    );
+    let database_pool = pool.clone();
+    let database = web::block(move || health::check_database(&database_pool)).await;
+    readiness.add("database", database.map_err(|e| e.to_string()));
 
-    match relation_exists {
-        true => (),
-        false => {
-            return Ok(HttpResponse::Forbidden().finish());
-        }
+    let migrations_pool = pool.clone();
+    let migrations = web::block(move || health::check_migrations(&migrations_pool)).await;
+    readiness.add("migrations", migrations.map_err(|e| e.to_string()));
+
+    readiness.add("publisher", health::check_actor(publish.get_ref()).await);
+    readiness.add("webhook_publisher", health::check_actor(webhook_publish.get_ref()).await);
+
+    match readiness.ready {
+        true => HttpResponse::Ok().json(readiness),
+        false => HttpResponse::ServiceUnavailable().json(readiness),
     }
+}
 
-    let account = account::get_account(&account_id, &conn).expect("Failed to get account.");
+async fn get_metrics(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    webhook_publish: web::Data<Addr<webhook_publisher::WebhookPublisher>>,
+) -> HttpResponse {
+    metrics::probe_mailbox("publisher", publish.get_ref()).await;
+    metrics::probe_mailbox("webhook_publisher", webhook_publish.get_ref()).await;
+    let (content_type, body) = metrics::render(&pool);
+    HttpResponse::Ok().content_type(content_type).body(body)
+}
 
-    db::create_device(
-        device_id,
-        device_type_id,
-        &conn
-    ).expect("Failed to create device");
+async fn ws_index(
+    auth: Option<BasicAuth>,
+    pool: web::Data<db::DbPool>,
+    r: HttpRequest,
+    stream: web::Payload,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    log_writer: web::Data<Addr<log_writer::LogWriter>>,
+    api_cipher_key: web::Data<ApiCipherKey>,
+    config: web::Data<config::Config>,
+    draining: web::Data<shutdown::Draining>,
+) -> Result<HttpResponse, Error> {
+    // Devices should reconnect to the next server
+    if draining.is_draining() {
+        return Ok(handshake::Rejection::unavailable("Server is shutting down").respond(&r));
+    }
+    let handshake = validate_handshake(auth, &pool, &r, &api_cipher_key);
+    let (account_id, device_id, device_type_id, will, account) = match handshake {
+        Ok(h) => h,
+        Err(rejection) => return Ok(rejection.respond(&r)),
+    };
+    Span::current()
+        .record("account_id", &account_id.as_str())
+        .record("device_id", &device_id.as_str());
 
+    let peer_address = r.peer_addr().map(|addr| addr.to_string());
     let res = ws::start(websocket::WebSocket::new(
         account_id,
-        device_id.to_string(),
-        device_type_id.to_string(),
+        device_id,
+        device_type_id,
+        will,
+        peer_address,
         account.max_requests_per_minute,
         publish.get_ref().clone(),
         pool.clone(),
+        log_writer.get_ref().clone(),
+        &config,
     ), &r, stream);
     res
 }
 
+// Validate websocket connection, returning the account_id,
+// device_id, device_type_id, last will and account of the
+// connecting device
+fn validate_handshake(
+    auth: Option<BasicAuth>,
+    pool: &db::DbPool,
+    r: &HttpRequest,
+    api_cipher_key: &ApiCipherKey,
+) -> Result<(String, String, String, Option<publisher::Will>, account::AccountData), handshake::Rejection> {
+    let auth = match auth {
+        Some(a) => a,
+        None => return Err(handshake::Rejection::unauthorized("Missing basic auth credentials")),
+    };
+
+    let device_id = handshake::header_value(r, "Device-Id")?;
+    handshake::validate_id("Device-Id", device_id)?;
+    let device_type_id = handshake::header_value(r, "Device-Type-Id")?;
+    handshake::validate_id("Device-Type-Id", device_type_id)?;
+    let will = handshake::will(r)?;
+
+    let conn = match pool.get() {
+        Ok(c) => c,
+        Err(_) => return Err(handshake::Rejection::unavailable("Unable to get a db connection")),
+    };
+
+    let account_id = match auth::authenticate_connection(auth, &api_cipher_key.0, &conn) {
+        Ok(id) => id,
+        Err(_) => return Err(handshake::Rejection::unauthorized("Invalid account id or api key")),
+    };
+
+    if !db::device_type_relation_exists(&account_id, device_type_id, &conn) {
+        return Err(handshake::Rejection::forbidden("Device type does not belong to account"));
+    }
+
+    let account = match account::get_account(&account_id, &conn) {
+        Ok(a) => a,
+        Err(_) => return Err(handshake::Rejection::not_found("Account not found")),
+    };
+
+    if account.suspended {
+        return Err(handshake::Rejection::forbidden("Account is suspended"));
+    }
+
+    // Reserved topics referenced by id rather than name
+    if let Some(will) = &will {
+        if let Ok(topic) = db::get_topic(&account_id, &will.topic, &conn) {
+            if db::is_reserved_topic_name(&topic.name) {
+                return Err(handshake::Rejection::forbidden("Will-Topic can't be a reserved topic"));
+            }
+        }
+    }
+
+    match db::create_device(device_id, &account_id, device_type_id, &conn) {
+        Ok(_) => (),
+        Err(diesel::result::Error::NotFound) => {
+            return Err(handshake::Rejection::forbidden("Device has been deleted"));
+        },
+        Err(_) => return Err(handshake::Rejection::bad_request("Unable to register device")),
+    }
+
+    Ok((account_id, device_id.to_string(), device_type_id.to_string(), will, account))
+}
+
 #[derive(Debug, Deserialize)]
 struct MessagePost {
     topics: Vec<String>,
@@ -116,11 +326,12 @@ struct MessagePost {
 }
 
 async fn message(
+    account: auth::AuthenticatedAccount,
     r: HttpRequest,
     body: web::Json<MessagePost>,
     publish: web::Data<Addr<publisher::Publisher>>
 ) -> HttpResponse {
-    let account_id: &str = r.headers().get("Account-Id").unwrap().to_str().unwrap();
+    let account_id: &str = &account.account_id;
     let uri = r.peer_addr();
     let sender = publisher::Sender::Address(uri);
 
@@ -148,34 +359,394 @@ struct DeviceTypePost {
     description: Option<String>,
 }
 
-#[allow(unused)]
-async fn device_types_post(pool: web::Data<db::DbPool>, r: HttpRequest, body: web::Json<DeviceTypePost>) -> Result<HttpResponse, Error> {
-    let account_id: &str = r.headers().get("Account-Id").unwrap().to_str().unwrap();
+async fn device_types_post(pool: web::Data<db::DbPool>, account: auth::AuthenticatedAccount, body: web::Json<DeviceTypePost>) -> Result<HttpResponse, Error> {
+    let account_id: &str = &account.account_id;
     let conn = pool.get().expect("Failed to get a db connection");
 
-    db::create_device_type(
+    let result = db::create_device_type(
         &body.name,
         account_id,
         body.description.as_deref(),
         &conn
     );
+    match result {
+        Ok(device_type) => return_body(device_type),
+        Err(e) => Ok(db_error_response(e)),
+    }
+}
+
+async fn get_device_types(
+    pool: web::Data<db::DbPool>,
+    account: auth::AuthenticatedAccount,
+    query: web::Query<PageQuery>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+
+    match load_listing(&query.cursor, query.limit, |after, limit| db::get_device_types(account_id, after, limit, &conn)) {
+        Ok(listing) => return_body(listing),
+        Err(response) => Ok(response),
+    }
+}
+
+async fn get_device_type(pool: web::Data<db::DbPool>, account: auth::AuthenticatedAccount, r: HttpRequest) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+    let device_type_id = r.match_info().query("id");
+
+    match db::get_device_type(account_id, device_type_id, &conn) {
+        Ok(device_type) => return_body(device_type),
+        Err(e) => Ok(db_error_response(e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceTypePatch {
+    name: Option<String>,
+    description: Option<String>,
+}
+
+async fn device_type_patch(
+    pool: web::Data<db::DbPool>,
+    account: auth::AuthenticatedAccount,
+    r: HttpRequest,
+    body: web::Json<DeviceTypePatch>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+    let device_type_id = r.match_info().query("id");
+
+    if body.name.is_none() && body.description.is_none() {
+        return Ok(HttpResponse::BadRequest().body("Nothing to update"));
+    }
+
+    let changes = models::DeviceTypeChangeset {
+        name: body.name.as_deref(),
+        description: body.description.as_deref(),
+    };
+    match db::update_device_type(account_id, device_type_id, &changes, &conn) {
+        Ok(device_type) => return_body(device_type),
+        Err(e) => Ok(db_error_response(e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceTypeDeleteQuery {
+    cascade: Option<bool>,
+}
+
+async fn device_type_delete(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    account: auth::AuthenticatedAccount,
+    r: HttpRequest,
+    query: web::Query<DeviceTypeDeleteQuery>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+    let device_type_id = r.match_info().query("id");
+
+    if let Err(e) = db::get_device_type(account_id, device_type_id, &conn) {
+        return Ok(db_error_response(e));
+    }
+
+    // Devices are only removed along with their type
+    // when explicitly asked for
+    if !query.cascade.unwrap_or(false) {
+        match db::device_type_has_devices(device_type_id, &conn) {
+            Ok(false) => (),
+            Ok(true) => return Ok(HttpResponse::Conflict().body(
+                "Device type has devices, use ?cascade=true to delete them"
+            )),
+            Err(e) => return Ok(db_error_response(e)),
+        }
+    }
+
+    let device_ids = match db::delete_device_type(account_id, device_type_id, &conn) {
+        Ok(ids) => ids,
+        Err(e) => return Ok(db_error_response(e)),
+    };
+
+    // Close the live connections of the removed devices
+    for device_id in device_ids {
+        publish.do_send(publisher::DisconnectDevice {
+            account_id: account_id.to_owned(),
+            device_id,
+            reason: "device type deleted".to_string(),
+        });
+    }
     Ok(HttpResponse::Ok().finish())
 }
 
-async fn get_device_types(pool: web::Data<db::DbPool>, r: HttpRequest) -> Result<HttpResponse, Error> {
+// device_ids of an account's currently connected devices
+async fn online_device_ids(
+    publish: &Addr<publisher::Publisher>,
+    account_id: &str,
+) -> HashSet<String> {
+    match publish.send(publisher::GetAccountActivity(account_id.to_owned())).await {
+        Ok(Some(devices)) => devices.into_iter().map(|d| d.device_id).collect(),
+        _ => HashSet::new(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DevicesQuery {
+    device_type_id: Option<String>,
+    tag: Option<String>,
+    online: Option<bool>,
+    cursor: Option<String>,
+    limit: Option<u32>,
+}
+
+async fn get_devices(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    account: auth::AuthenticatedAccount,
+    query: web::Query<DevicesQuery>,
+) -> Result<HttpResponse, Error> {
     let conn = pool.get().expect("Failed to get a db connection");
-    let account_id: &str = r.headers().get("Account-Id").unwrap().to_str().unwrap();
+    let account_id: &str = &account.account_id;
 
-    let result = db::get_device_types(account_id, &conn);
-    return_result_body(result)
+    let listing = load_listing(&query.cursor, query.limit, |after, limit| db::get_devices(
+        account_id,
+        query.device_type_id.as_deref(),
+        query.tag.as_deref(),
+        after,
+        limit,
+        &conn,
+    ));
+    let mut listing = match listing {
+        Ok(l) => l,
+        Err(response) => return Ok(response),
+    };
+
+    // Presence lives in the publisher, so the online filter is
+    // applied per page and a page can hold fewer than limit items
+    let online = online_device_ids(&publish, account_id).await;
+    let items = listing.items_mut();
+    *items = items
+        .drain(..)
+        .map(|mut device| {
+            device.online = online.contains(&device.id);
+            device
+        })
+        .filter(|device| query.online.map_or(true, |o| o == device.online))
+        .collect();
+    return_body(listing)
 }
 
-async fn get_topics(pool: web::Data<db::DbPool>, r: HttpRequest) -> Result<HttpResponse, Error> {
+async fn get_device(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    account: auth::AuthenticatedAccount,
+    r: HttpRequest,
+) -> Result<HttpResponse, Error> {
     let conn = pool.get().expect("Failed to get a db connection");
-    let account_id: &str = r.headers().get("Account-Id").unwrap().to_str().unwrap();
+    let account_id: &str = &account.account_id;
+    let device_id = r.match_info().query("id");
 
-    let result = db::get_topics(account_id, &conn);
-    return_result_body(result)
+    let mut device = match db::get_device(account_id, device_id, &conn) {
+        Ok(d) => d,
+        Err(e) => return Ok(db_error_response(e)),
+    };
+    device.online = online_device_ids(&publish, account_id).await.contains(&device.id);
+    return_body(device)
+}
+
+const MAX_DEVICE_TAGS: usize = 32;
+const MAX_DEVICE_TAG_LENGTH: usize = 64;
+
+#[derive(Debug, Deserialize)]
+struct DevicePatch {
+    metadata: Option<Value>,
+    tags: Option<Vec<String>>,
+}
+
+async fn device_patch(
+    pool: web::Data<db::DbPool>,
+    account: auth::AuthenticatedAccount,
+    r: HttpRequest,
+    body: web::Json<DevicePatch>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+    let device_id = r.match_info().query("id");
+    let body = body.into_inner();
+
+    if body.metadata.is_none() && body.tags.is_none() {
+        return Ok(HttpResponse::BadRequest().body("Nothing to update"));
+    }
+    if let Some(metadata) = &body.metadata {
+        if !metadata.is_object() {
+            return Ok(HttpResponse::BadRequest().body("metadata must be a JSON object"));
+        }
+    }
+    if let Some(tags) = &body.tags {
+        if tags.len() > MAX_DEVICE_TAGS {
+            return Ok(HttpResponse::BadRequest().body(format!("A device can have at most {} tags", MAX_DEVICE_TAGS)));
+        }
+        if tags.iter().any(|t| t.is_empty() || t.len() > MAX_DEVICE_TAG_LENGTH) {
+            return Ok(HttpResponse::BadRequest().body(format!("Tags must be between 1 and {} characters", MAX_DEVICE_TAG_LENGTH)));
+        }
+    }
+
+    let changes = models::DeviceChangeset {
+        metadata: body.metadata,
+        tags: body.tags,
+    };
+    match db::update_device(account_id, device_id, &changes, &conn) {
+        Ok(device) => return_body(device),
+        Err(e) => Ok(db_error_response(e)),
+    }
+}
+
+async fn device_delete(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    account: auth::AuthenticatedAccount,
+    r: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+    let device_id = r.match_info().query("id");
+
+    match db::delete_device(account_id, device_id, &conn) {
+        Ok(_) => (),
+        Err(e) => return Ok(db_error_response(e)),
+    };
+
+    publish.do_send(publisher::DisconnectDevice {
+        account_id: account_id.to_owned(),
+        device_id: device_id.to_owned(),
+        reason: "device deleted".to_string(),
+    });
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Allow a deleted device to connect again
+async fn device_restore(
+    pool: web::Data<db::DbPool>,
+    account: auth::AuthenticatedAccount,
+    r: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+    let device_id = r.match_info().query("id");
+
+    match db::restore_device(account_id, device_id, &conn) {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(e) => Ok(db_error_response(e)),
+    }
+}
+
+async fn get_device_shadow(
+    pool: web::Data<db::DbPool>,
+    account: auth::AuthenticatedAccount,
+    r: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+    let device_id = r.match_info().query("id");
+
+    if let Err(e) = db::get_device(account_id, device_id, &conn) {
+        return Ok(db_error_response(e));
+    }
+    match shadow::get_shadow(account_id, device_id, &conn) {
+        Ok(s) => return_body(s),
+        Err(e) => Ok(db_error_response(e)),
+    }
+}
+
+async fn get_device_sessions(
+    pool: web::Data<db::DbPool>,
+    account: auth::AuthenticatedAccount,
+    query: web::Query<PageQuery>,
+    r: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+    let device_id = r.match_info().query("id");
+
+    let (cursor, limit) = match page_params(&query.cursor, query.limit, DEFAULT_PAGE_SIZE) {
+        Ok(p) => p,
+        Err(response) => return Ok(response),
+    };
+
+    if let Err(e) = db::get_device(account_id, device_id, &conn) {
+        return Ok(db_error_response(e));
+    }
+    match db::get_device_sessions(account_id, device_id, cursor.as_ref(), limit, &conn) {
+        Ok(page) => return_body(page),
+        Err(e) => Ok(db_error_response(e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ShadowPatch {
+    state: Value,
+    // The shadow version the client last saw
+    version: Option<i64>,
+}
+
+async fn device_shadow_desired_patch(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    account: auth::AuthenticatedAccount,
+    r: HttpRequest,
+    body: web::Json<ShadowPatch>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+    let device_id = r.match_info().query("id");
+
+    if !body.state.is_object() {
+        return Ok(HttpResponse::BadRequest().body("state must be a JSON object"));
+    }
+    if let Err(e) = db::get_device(account_id, device_id, &conn) {
+        return Ok(db_error_response(e));
+    }
+
+    let result = shadow::update_shadow(
+        account_id,
+        device_id,
+        shadow::ShadowDocument::Desired,
+        &body.state,
+        body.version,
+        &conn,
+    );
+    let updated = match result {
+        Ok(s) => s,
+        Err(e @ shadow::ShadowError::VersionConflict(_)) => {
+            return Ok(HttpResponse::Conflict().body(e.to_string()));
+        },
+        Err(shadow::ShadowError::Database(e)) => return Ok(db_error_response(e)),
+    };
+
+    if updated.has_delta() {
+        publish.do_send(publisher::SendShadowEvent {
+            account_id: account_id.to_owned(),
+            device_id: device_id.to_owned(),
+            event: shadow::ShadowEvent::ShadowDelta {
+                version: updated.version,
+                state: updated.delta.clone(),
+            },
+        });
+    }
+    return_body(updated)
+}
+
+async fn get_topics(
+    pool: web::Data<db::DbPool>,
+    account: auth::AuthenticatedAccount,
+    query: web::Query<PageQuery>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+
+    match load_listing(&query.cursor, query.limit, |after, limit| db::get_topics(account_id, after, limit, &conn)) {
+        Ok(listing) => return_body(listing),
+        Err(response) => Ok(response),
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -184,9 +755,18 @@ struct TopicsPost {
     description: Option<String>,
 }
 
-async fn topics_post(pool: web::Data<db::DbPool>, r: HttpRequest, body: web::Json<TopicsPost>) -> Result<HttpResponse, Error> {
+async fn topics_post(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    account: auth::AuthenticatedAccount,
+    body: web::Json<TopicsPost>,
+) -> Result<HttpResponse, Error> {
     let conn = pool.get().expect("Failed to get a db connection");
-    let account_id: &str = r.headers().get("Account-Id").unwrap().to_str().unwrap();
+    let account_id: &str = &account.account_id;
+
+    if db::is_reserved_topic_name(&body.name) {
+        return Ok(HttpResponse::BadRequest().body("Topic names starting with $ are reserved"));
+    }
 
     let result = db::create_topic(
         &body.name,
@@ -194,15 +774,120 @@ async fn topics_post(pool: web::Data<db::DbPool>, r: HttpRequest, body: web::Jso
         body.description.as_deref(),
         &conn
     );
-    return_result_body(result)
+    match result {
+        Ok(topic) => {
+            publish.do_send(publisher::UpsertTopic {
+                account_id: account_id.to_owned(),
+                topic_id: topic.id.clone(),
+                name: topic.name.clone(),
+            });
+            return_body(topic)
+        },
+        Err(e) => Ok(db_error_response(e)),
+    }
 }
 
-async fn get_webhooks(pool: web::Data<db::DbPool>, r: HttpRequest) -> Result<HttpResponse, Error> {
+async fn get_topic(pool: web::Data<db::DbPool>, account: auth::AuthenticatedAccount, r: HttpRequest) -> Result<HttpResponse, Error> {
     let conn = pool.get().expect("Failed to get a db connection");
-    let account_id: &str = r.headers().get("Account-Id").unwrap().to_str().unwrap();
+    let account_id: &str = &account.account_id;
+    let topic_id = r.match_info().query("id");
 
-    let webhooks = db::get_webhooks(account_id, &conn);
-    Ok(HttpResponse::Ok().content_type("application/json").body(serde_json::to_string(&webhooks).unwrap()))
+    match db::get_topic(account_id, topic_id, &conn) {
+        Ok(topic) => return_body(topic),
+        Err(e) => Ok(db_error_response(e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TopicPatch {
+    name: Option<String>,
+    description: Option<String>,
+}
+
+async fn topic_patch(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    account: auth::AuthenticatedAccount,
+    r: HttpRequest,
+    body: web::Json<TopicPatch>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+    let topic_id = r.match_info().query("id");
+
+    if body.name.is_none() && body.description.is_none() {
+        return Ok(HttpResponse::BadRequest().body("Nothing to update"));
+    }
+    if body.name.as_deref().map_or(false, db::is_reserved_topic_name) {
+        return Ok(HttpResponse::BadRequest().body("Topic names starting with $ are reserved"));
+    }
+    match db::get_topic(account_id, topic_id, &conn) {
+        Ok(topic) if db::is_reserved_topic_name(&topic.name) => {
+            return Ok(HttpResponse::BadRequest().body("Reserved topics can't be changed"));
+        },
+        Ok(_) => (),
+        Err(e) => return Ok(db_error_response(e)),
+    }
+
+    let changes = models::TopicChangeset {
+        name: body.name.as_deref(),
+        description: body.description.as_deref(),
+    };
+    match db::update_topic(account_id, topic_id, &changes, &conn) {
+        Ok(topic) => {
+            publish.do_send(publisher::UpsertTopic {
+                account_id: account_id.to_owned(),
+                topic_id: topic.id.clone(),
+                name: topic.name.clone(),
+            });
+            return_body(topic)
+        },
+        Err(e) => Ok(db_error_response(e)),
+    }
+}
+
+async fn topic_delete(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    account: auth::AuthenticatedAccount,
+    r: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+    let topic_id = r.match_info().query("id");
+
+    match db::get_topic(account_id, topic_id, &conn) {
+        Ok(topic) if db::is_reserved_topic_name(&topic.name) => {
+            return Ok(HttpResponse::BadRequest().body("Reserved topics can't be deleted"));
+        },
+        Ok(_) => (),
+        Err(e) => return Ok(db_error_response(e)),
+    }
+
+    match db::delete_topic(account_id, topic_id, &conn) {
+        Ok(_) => (),
+        Err(e) => return Ok(db_error_response(e)),
+    };
+
+    publish.do_send(publisher::RemoveTopic {
+        account_id: account_id.to_owned(),
+        topic_id: topic_id.to_owned(),
+    });
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn get_webhooks(
+    pool: web::Data<db::DbPool>,
+    account: auth::AuthenticatedAccount,
+    query: web::Query<PageQuery>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+
+    match load_listing(&query.cursor, query.limit, |after, limit| db::get_webhooks(account_id, after, limit, &conn)) {
+        Ok(listing) => return_body(listing),
+        Err(response) => Ok(response),
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -210,9 +895,9 @@ struct WebhooksPost {
     url: String,
 }
 
-async fn webhooks_post(pool: web::Data<db::DbPool>, r: HttpRequest, body: web::Json<WebhooksPost>) -> Result<HttpResponse, Error> {
+async fn webhooks_post(pool: web::Data<db::DbPool>, account: auth::AuthenticatedAccount, body: web::Json<WebhooksPost>) -> Result<HttpResponse, Error> {
     let conn = pool.get().expect("Failed to get a db connection");
-    let account_id: &str = r.headers().get("Account-Id").unwrap().to_str().unwrap();
+    let account_id: &str = &account.account_id;
 
     let result = db::create_webhook(
         account_id,
@@ -275,35 +960,263 @@ async fn webhook_delete(pool: web::Data<db::DbPool>, r: HttpRequest) -> Result<H
 
 #[derive(Deserialize, Debug)]
 struct LogQuery {
+    // Offset pagination unless a cursor is given, an empty
+    // cursor starting from the newest log
     page: Option<u32>,
-    limit: Option<u32>
+    cursor: Option<String>,
+    limit: Option<u32>,
+    level: Option<String>,
+    device_id: Option<String>,
+    device_type_id: Option<String>,
+    message: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+    // JSON document the log data must contain
+    contains: Option<String>,
+}
+
+impl LogQuery {
+    fn filter(&self) -> Result<logging::LogFilter, HttpResponse> {
+        let contains = match &self.contains {
+            Some(c) => match serde_json::from_str::<Value>(c) {
+                Ok(v) => Some(v),
+                Err(_) => return Err(HttpResponse::BadRequest().body("contains must be a JSON document")),
+            },
+            None => None,
+        };
+        Ok(logging::LogFilter {
+            level: self.level.clone(),
+            device_id: self.device_id.clone(),
+            device_type_id: self.device_type_id.clone(),
+            message: self.message.clone(),
+            from: self.from,
+            to: self.to,
+            contains,
+        })
+    }
 }
 
 async fn get_logs(
     pool: web::Data<db::DbPool>,
-    r: HttpRequest,
+    account: auth::AuthenticatedAccount,
     query: web::Query<LogQuery>
 ) -> Result<HttpResponse, Error> {
     let conn = pool.get().expect("Failed to get a db connection");
-    let account_id: &str = r.headers().get("Account-Id").unwrap().to_str().unwrap();
+    let account_id: &str = &account.account_id;
 
-    let result = logging::paginated_logs(
-        account_id,
-        query.page,
-        query.limit,
-        &conn
-    );
+    let filter = match query.filter() {
+        Ok(f) => f,
+        Err(response) => return Ok(response),
+    };
+
+    // Clients from before cursor pagination pass page and
+    // limit, or nothing, and keep getting {items, total_pages}
+    let cursor = match &query.cursor {
+        Some(c) if c.is_empty() => None,
+        Some(c) => Some(c.clone()),
+        None => {
+            let result = logging::paginated_logs(
+                account_id,
+                &filter,
+                query.page,
+                query.limit,
+                &conn
+            );
+            return return_result_body(result);
+        },
+    };
 
+    let (cursor, limit) = match page_params(&cursor, query.limit, 10) {
+        Ok(p) => p,
+        Err(response) => return Ok(response),
+    };
+
+    let result = logging::cursor_logs(account_id, &filter, cursor.as_ref(), limit, &conn);
     return_result_body(result)
 }
 
+#[derive(Debug, Deserialize)]
+struct LogTailQuery {
+    level: Option<String>,
+    device_id: Option<String>,
+}
+
+// Server-sent events for each new log of the account as it
+// is written, optionally only of one level or device
+async fn tail_logs(
+    log_writer: web::Data<Addr<log_writer::LogWriter>>,
+    account: auth::AuthenticatedAccount,
+    query: web::Query<LogTailQuery>,
+    draining: web::Data<shutdown::Draining>,
+) -> Result<HttpResponse, Error> {
+    // Tails are closed while draining, a new one would
+    // hold up the shutdown
+    if draining.is_draining() {
+        return Ok(HttpResponse::ServiceUnavailable().body("server is shutting down"));
+    }
+    let query = query.into_inner();
+    let (sender, receiver) = sse::channel();
+    log_writer.do_send(log_writer::TailLogs {
+        account_id: account.account_id.clone(),
+        level: query.level,
+        device_id: query.device_id,
+        sender,
+    });
+
+    Ok(sse::response(receiver))
+}
+
+// A short-lived token for GET /subscribe. Browsers can't
+// sign requests, so the webapp fetches one and hands it to
+// the dashboard.
+async fn subscribe_token(
+    hmac_key: web::Data<HmacKey>,
+    account: auth::AuthenticatedAccount,
+) -> Result<HttpResponse, Error> {
+    let (token, expires_at) = auth::issue_subscribe_token(&account.account_id, &hmac_key.0);
+    Ok(HttpResponse::Ok().json(json!({
+        "token": token,
+        "expires_at": expires_at,
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscribeQuery {
+    token: String,
+    // Comma separated topic ids or names
+    topics: String,
+}
+
+// Server-sent events for each message published to the
+// given topics of the account, for clients such as browsers
+// that can't speak the device websocket protocol. An
+// EventSource can't set headers, so the account comes from
+// a token in the query rather than a signed request.
+async fn subscribe(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    hmac_key: web::Data<HmacKey>,
+    query: web::Query<SubscribeQuery>,
+    draining: web::Data<shutdown::Draining>,
+) -> Result<HttpResponse, Error> {
+    let account = auth::verify_subscribe_token(&query.token, &hmac_key.0, &pool).await?;
+    if draining.is_draining() {
+        return Ok(HttpResponse::ServiceUnavailable().body("server is shutting down"));
+    }
+    let topics: Vec<String> = query.topics
+        .split(',')
+        .map(|topic| topic.trim())
+        .filter(|topic| !topic.is_empty())
+        .map(|topic| topic.to_string())
+        .collect();
+    if topics.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("topics must not be empty"));
+    }
+
+    let (sender, receiver) = sse::channel();
+    let subscription = publish.send(publisher::Subscribe {
+        account_id: account.account_id,
+        topics,
+        sender,
+    }).await;
+    match subscription {
+        Ok(Ok(_)) => (),
+        Ok(Err(publisher::SubscribeError::UnknownTopic(topic))) => {
+            return Ok(HttpResponse::NotFound().body(format!("topic {} not found", topic)));
+        },
+        Ok(Err(publisher::SubscribeError::TooManySubscribers)) => {
+            return Ok(HttpResponse::TooManyRequests().body("too many open subscriptions"));
+        },
+        Err(_) => return Ok(HttpResponse::ServiceUnavailable().finish()),
+    };
+
+    Ok(sse::response(receiver))
+}
+
+const LOG_EXPORT_BATCH_SIZE: i64 = 1000;
+
+#[derive(Debug, Deserialize)]
+struct LogExportQuery {
+    format: Option<String>,
+}
+
+// Streams every log matching the /logs filters. Diesel 1.4
+// can't stream rows from a cursor, so logs are read in keyset
+// batches as the client consumes the response and an export
+// never holds more than one batch in memory. The status is
+// sent before the first batch, so the last line is a trailer
+// saying whether the export completed or failed part way.
+async fn export_logs(
+    pool: web::Data<db::DbPool>,
+    account: auth::AuthenticatedAccount,
+    query: web::Query<LogQuery>,
+    export: web::Query<LogExportQuery>,
+) -> Result<HttpResponse, Error> {
+    let format = match export.format.as_deref().map_or(Some(logging::ExportFormat::Ndjson), logging::ExportFormat::parse) {
+        Some(f) => f,
+        None => return Ok(HttpResponse::BadRequest().body("format must be ndjson or csv")),
+    };
+    let filter = match query.filter() {
+        Ok(f) => f,
+        Err(response) => return Ok(response),
+    };
+    let account_id = account.account_id.clone();
+
+    // (cursor to continue after, is first batch, logs written
+    // so far), None once the trailer is written
+    let start: Option<(Option<pagination::Cursor>, bool, usize)> = Some((None, true, 0));
+    let body = stream::unfold(start, move |state| {
+        let pool = pool.clone();
+        let account_id = account_id.clone();
+        let filter = filter.clone();
+        async move {
+            let (after, first, count) = state?;
+            let result = web::block(move || {
+                let conn = pool.get().map_err(|e| e.to_string())?;
+                logging::cursor_logs(&account_id, &filter, after.as_ref(), LOG_EXPORT_BATCH_SIZE, &conn)
+                    .map_err(|e| e.to_string())
+            }).await;
+
+            let mut chunk = String::new();
+            if first {
+                chunk.push_str(format.header());
+            }
+            let next = match result {
+                Ok(page) => {
+                    let count = count + page.items.len();
+                    chunk.push_str(&format.encode(&page.items));
+                    let next = page.next_cursor
+                        .as_ref()
+                        .and_then(|c| pagination::Cursor::decode(c))
+                        .map(|c| (Some(c), false, count));
+                    if next.is_none() {
+                        chunk.push_str(&format.trailer(count, None));
+                    }
+                    next
+                },
+                Err(e) => {
+                    error!(error = ?e, "Error exporting logs");
+                    chunk.push_str(&format.trailer(count, Some("log export failed")));
+                    None
+                },
+            };
+            Some((Ok::<_, Error>(web::Bytes::from(chunk)), next))
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .header("Content-Disposition", format!("attachment; filename=\"logs.{}\"", format.extension()))
+        .streaming(body))
+}
+
 async fn get_api_key(
     pool: web::Data<db::DbPool>,
     api_cipher_key: web::Data<ApiCipherKey>,
-    r: HttpRequest,
+    account: auth::AuthenticatedAccount,
 ) -> Result<HttpResponse, Error> {
     let conn = pool.get().expect("Failed to get a db connection");
-    let account_id: &str = r.headers().get("Account-Id").unwrap().to_str().unwrap();
+    let account_id: &str = &account.account_id;
 
     let result = account::get_api_key(account_id, &api_cipher_key.0, &conn);
     return_result_body(result)
@@ -312,12 +1225,18 @@ async fn get_api_key(
 async fn create_account(
     pool: web::Data<db::DbPool>,
     api_cipher_key: web::Data<ApiCipherKey>,
-    r: HttpRequest,
+    config: web::Data<config::Config>,
+    account: auth::AuthenticatedAccount,
 ) -> Result<HttpResponse, Error> {
     let conn = pool.get().expect("Failed to get a db connection");
-    let account_id: &str = r.headers().get("Account-Id").unwrap().to_str().unwrap();
+    let account_id: &str = &account.account_id;
 
-    match account::create_account(account_id, &api_cipher_key.0, &conn) {
+    let limits = account::NewAccountLimits {
+        max_requests_per_minute: config.default_max_requests_per_minute,
+        max_connections: config.default_max_connections,
+        log_retention_days: config.default_log_retention_days,
+    };
+    match account::create_account(account_id, &api_cipher_key.0, &limits, &conn) {
         Ok(_) => Ok(HttpResponse::Ok().finish()),
         Err(_) => {
             Ok(HttpResponse::BadRequest().finish())
@@ -327,20 +1246,144 @@ async fn create_account(
 
 async fn get_account(
     pool: web::Data<db::DbPool>,
-    r: HttpRequest,
+    account: auth::AuthenticatedAccount,
 ) -> Result<HttpResponse, Error> {
     let conn = pool.get().expect("Failed to get a db connection");
-    let account_id: &str = r.headers().get("Account-Id").unwrap().to_str().unwrap();
+    let account_id: &str = &account.account_id;
 
     let result = account::get_account(account_id, &conn);
     return_result_body(result)
 }
 
-async fn get_account_activity(
+async fn get_log_retention(pool: web::Data<db::DbPool>, account: auth::AuthenticatedAccount) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+
+    match account::get_log_retention(account_id, &conn) {
+        Ok(retention) => return_body(retention),
+        Err(e) => Ok(db_error_response(e)),
+    }
+}
+
+// Replaces both retention limits, null removes a limit
+async fn update_log_retention(
+    pool: web::Data<db::DbPool>,
+    account: auth::AuthenticatedAccount,
+    body: web::Json<account::LogRetention>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id: &str = &account.account_id;
+
+    let positive = |limit: Option<i32>| limit.map_or(true, |l| l > 0);
+    if !positive(body.days) || !positive(body.max_rows) {
+        return Ok(HttpResponse::BadRequest().body("Retention limits must be greater than zero"));
+    }
+
+    match account::update_log_retention(account_id, &body, &conn) {
+        Ok(retention) => return_body(retention),
+        Err(e) => Ok(db_error_response(e)),
+    }
+}
+
+// Admin only, acts on the account in the path
+async fn update_account_limits(
+    pool: web::Data<db::DbPool>,
     publish: web::Data<Addr<publisher::Publisher>>,
     r: HttpRequest,
+    body: web::Json<account::AccountLimits>,
 ) -> Result<HttpResponse, Error> {
-    let account_id: &str = r.headers().get("Account-Id").unwrap().to_str().unwrap();
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id = r.match_info().query("id");
+
+    let positive = |limit: Option<i32>| limit.map_or(true, |l| l > 0);
+    if !positive(body.max_requests_per_minute) || !positive(body.max_connections) {
+        return Ok(HttpResponse::BadRequest().body("Limits must be greater than zero"));
+    }
+
+    let updated = match account::update_account_limits(account_id, &body, &conn) {
+        Ok(a) => a,
+        Err(diesel::result::Error::NotFound) => return Ok(HttpResponse::NotFound().finish()),
+        Err(_) => return Ok(HttpResponse::BadRequest().finish()),
+    };
+
+    publish.do_send(publisher::UpdateAccountLimits {
+        account_id: account_id.to_owned(),
+        max_requests_per_minute: updated.max_requests_per_minute,
+        max_connections: updated.max_connections,
+    });
+    return_body(updated)
+}
+
+fn set_account_suspended(
+    pool: &db::DbPool,
+    publish: &Addr<publisher::Publisher>,
+    account_id: &str,
+    suspended: bool,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+
+    match account::set_account_suspended(account_id, suspended, &conn) {
+        Ok(_) => (),
+        Err(diesel::result::Error::NotFound) => return Ok(HttpResponse::NotFound().finish()),
+        Err(_) => return Ok(HttpResponse::BadRequest().finish()),
+    };
+
+    if suspended {
+        publish.do_send(publisher::DisconnectAccount {
+            account_id: account_id.to_owned(),
+            reason: "account suspended".to_string(),
+            deleted: false,
+        });
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Admin only
+async fn suspend_account(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    r: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    set_account_suspended(&pool, &publish, r.match_info().query("id"), true)
+}
+
+// Admin only
+async fn resume_account(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    r: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    set_account_suspended(&pool, &publish, r.match_info().query("id"), false)
+}
+
+// Admin only
+async fn delete_account(
+    pool: web::Data<db::DbPool>,
+    publish: web::Data<Addr<publisher::Publisher>>,
+    r: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get().expect("Failed to get a db connection");
+    let account_id = r.match_info().query("id");
+
+    match account::delete_account(account_id, &conn) {
+        Ok(_) => (),
+        Err(diesel::result::Error::NotFound) => return Ok(HttpResponse::NotFound().finish()),
+        Err(_) => return Ok(HttpResponse::BadRequest().finish()),
+    };
+
+    publish.do_send(publisher::DisconnectAccount {
+        account_id: account_id.to_owned(),
+        reason: "account deleted".to_string(),
+        deleted: true,
+    });
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn get_account_activity(
+    publish: web::Data<Addr<publisher::Publisher>>,
+    account: auth::AuthenticatedAccount,
+) -> Result<HttpResponse, Error> {
+    let account_id: &str = &account.account_id;
 
     let maybe_activity = publish.send(publisher::GetAccountActivity(account_id.to_owned())).await;
     match maybe_activity {
@@ -352,80 +1395,181 @@ async fn get_account_activity(
     }
 }
 
-struct HmacKey(String);
 struct ApiCipherKey(String);
 
-async fn validator<'a>(
-    req: ServiceRequest,
-    _credentials: BearerAuth,
-) -> Result<ServiceRequest, Error> {
-    let hmac_key = req.app_data::<HmacKey>().unwrap().into_inner();
-
-    let account_id: &str = req.headers().get("Account-Id").unwrap().to_str().unwrap();
-    let time: &str = req.headers().get("Time").unwrap().to_str().unwrap();
-    let path: &str = &req.uri().to_string();
-    let hmac_signature: &str = req.headers().get("Herd-Webapp-Signature").unwrap().to_str().unwrap();
-
-    let signature_good = auth::verify_hmac_sigature(
-        path,
-        account_id,
-        time,
-        hmac_signature,
-        &hmac_key.0
-    );
-
-    match signature_good {
-        true => Ok(req),
-        false => Err(error::ErrorUnauthorized("Unauthorized"))
-    }
-}
+struct HmacKey(String);
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_server=info,actix_web=info");
-    env_logger::init();
     dotenv::dotenv().ok();
+    let config = match config::Config::load() {
+        Ok(c) => c,
+        Err(e) => {
+            // The logger is configured from the config,
+            // so this can only go to stderr
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        },
+    };
+    telemetry::init(&config);
 
     // Comment to force rebuild
     let hmac_key = env::var("HMAC_KEY").expect("HMAC_KEY must be set");
     let api_cipher_key = env::var("API_CIPHER_KEY").expect("API_CIPHER_KEY must be set");
+    // Without a key the admin API isn't served, so existing
+    // deployments keep starting until they set one
+    let admin_api_key = env::var("ADMIN_API_KEY").ok().filter(|k| !k.is_empty());
+    if admin_api_key.is_none() {
+        warn!("ADMIN_API_KEY is not set, the admin API is disabled");
+    }
+    // Scrapers get their own key so they don't need admin
+    // access, falling back to the admin key
+    let metrics_api_key = env::var("METRICS_API_KEY").ok()
+        .filter(|k| !k.is_empty())
+        .or_else(|| admin_api_key.clone());
+    if metrics_api_key.is_none() {
+        warn!("METRICS_API_KEY and ADMIN_API_KEY are not set, /metrics is disabled");
+    }
 
-    let pool = db::init_pool();
-    let webhook_publisher_addr = webhook_publisher::WebhookPublisher::initialize(pool.clone()).start();
+    let pool = db::init_pool(config.pool_size);
+    // Sessions still open but no longer touched were left
+    // by a server that stopped without closing them
+    let stale_before = SystemTime::now() - log_writer::SESSION_STALE_AFTER;
+    match pool.get().map_err(|e| e.to_string()).and_then(|conn| {
+        db::close_open_device_sessions(publisher::DisconnectReason::ServerRestart.as_str(), stale_before, &conn)
+            .map_err(|e| e.to_string())
+    }) {
+        Ok(0) => (),
+        Ok(count) => info!(count, "Closed device sessions left open by a stopped server"),
+        Err(e) => error!(error = %e, "Error closing stale device sessions"),
+    }
+    let webhook_publisher_addr = webhook_publisher::WebhookPublisher::initialize(
+        pool.clone(),
+        config.webhook_refresh_interval(),
+    ).start();
+    let log_writer_pool = pool.clone();
+    let log_flush_interval = config.log_flush_interval();
+    let log_writer_addr = log_writer::LogWriter::start_in_arbiter(&Arbiter::new(), move |_| {
+        log_writer::LogWriter::initialize(log_writer_pool, log_flush_interval)
+    });
     let publisher_addr = publisher::Publisher::initialize(
         pool.clone(),
-        webhook_publisher_addr,
+        webhook_publisher_addr.clone(),
+        log_writer_addr.clone(),
+        config.max_subscribers_per_account,
+        config.topic_refresh_interval(),
     ).start();
 
+    let log_pruner_pool = pool.clone();
+    let log_prune_interval = config.log_prune_interval();
+    log_pruner::LogPruner::start_in_arbiter(&Arbiter::new(), move |_| {
+        log_pruner::LogPruner::initialize(log_pruner_pool, log_prune_interval)
+    });
+
+    let bind = config.bind.clone();
+    // Loaded up front so a bad certificate stops startup
+    let tls_cert = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => match tls::ReloadingCert::new(cert_path, key_path) {
+            Ok(cert) => Some(Arc::new(cert)),
+            Err(e) => {
+                error!(error = %e, "Invalid TLS certificate");
+                std::process::exit(1);
+            },
+        },
+        _ => None,
+    };
+
     let weak_publish_addr = publisher_addr.downgrade();
+    let drain_webhook_publisher_addr = webhook_publisher_addr.clone();
+    let drain_log_writer_addr = log_writer_addr.clone();
+    let draining = shutdown::Draining::default();
+    let shutdown_draining = draining.clone();
+    let shutdown_timeout = config.shutdown_timeout();
+    let reconnect_spread = config.reconnect_spread();
 
     let server = HttpServer::new(move || {
         App::new()
-            .wrap(middleware::Logger::default())
+            // The access log has the query string, which for
+            // /subscribe holds a token
+            .wrap(middleware::Logger::default().exclude("/subscribe"))
+            .wrap_fn(|req, srv| {
+                let span = telemetry::request_span(&req);
+                let response = {
+                    let _enter = span.enter();
+                    srv.call(req)
+                };
+                response.instrument(span)
+            })
             .data(pool.clone())
             .data(publisher_addr.clone())
-            .data(HmacKey(hmac_key.clone()))
+            .data(log_writer_addr.clone())
+            .data(webhook_publisher_addr.clone())
             .data(ApiCipherKey(api_cipher_key.clone()))
+            .data(HmacKey(hmac_key.clone()))
+            .data(config.clone())
+            .data(draining.clone())
             .service(web::resource("/").route(web::get().to(health_check)))
+            .service(web::resource("/healthz").route(web::get().to(liveness)))
+            .service(web::resource("/readyz").route(web::get().to(readiness)))
+            .configure(|cfg| if let Some(key) = &metrics_api_key {
+                cfg.service(web::resource("/metrics")
+                    .wrap(auth::TokenAuth::new(key.clone()))
+                    .route(web::get().to(get_metrics)));
+            })
             // websocket route
             .service(web::resource("/ws/").route(web::get().to(ws_index)))
-            .service(web::resource("/message").route(web::post().to(message)))
+            .service(web::resource("/message")
+                .wrap(auth::HmacAuth::new(hmac_key.clone(), pool.clone()))
+                .route(web::post().to(message)))
+            // Authenticated by the token in its query
+            .service(web::resource("/subscribe")
+                .route(web::get().to(subscribe)))
+            .configure(|cfg| if let Some(key) = &admin_api_key {
+                cfg.service(
+                    web::scope("/admin")
+                    .wrap(auth::TokenAuth::new(key.clone()))
+                    .service(web::resource("/accounts/{id}")
+                        .route(web::patch().to(update_account_limits))
+                        .route(web::delete().to(delete_account)))
+                    .service(web::resource("/accounts/{id}/suspend")
+                        .route(web::post().to(suspend_account)))
+                    .service(web::resource("/accounts/{id}/resume")
+                        .route(web::post().to(resume_account)))
+                );
+            })
             .service(
                 web::scope("/")
-                /*
-                    Not ideal, but currently using the HttpAuthentication
-                    middlewares. They require that the authorization header
-                    is present, so currently adding one, even though it's not
-                    used. Lower priority, but ideally make own middleware
-                    to then remove the unsued BearerAuth parameter in validator.
-                */
-                .wrap(HttpAuthentication::bearer(validator))
+                .wrap(auth::HmacAuth::new(hmac_key.clone(), pool.clone()))
                 .service(web::resource("/device_types")
                     .route(web::post().to(device_types_post))
                     .route(web::get().to(get_device_types)))
+                .service(web::resource("/device_types/{id}")
+                    .route(web::get().to(get_device_type))
+                    .route(web::patch().to(device_type_patch))
+                    .route(web::delete().to(device_type_delete)))
+                .service(web::resource("/devices")
+                    .route(web::get().to(get_devices)))
+                .service(web::resource("/devices/{id}")
+                    .route(web::get().to(get_device))
+                    .route(web::patch().to(device_patch))
+                    .route(web::delete().to(device_delete)))
+                .service(web::resource("/devices/{id}/restore")
+                    .route(web::post().to(device_restore)))
+                .service(web::resource("/devices/{id}/sessions")
+                    .route(web::get().to(get_device_sessions)))
+                .service(web::resource("/devices/{id}/shadow")
+                    .route(web::get().to(get_device_shadow)))
+                .service(web::resource("/devices/{id}/shadow/desired")
+                    .route(web::patch().to(device_shadow_desired_patch)))
                 .service(web::resource("/topics")
                     .route(web::get().to(get_topics))
                     .route(web::post().to(topics_post)))
+                .service(web::resource("/topics/{id}")
+                    .route(web::get().to(get_topic))
+                    .route(web::patch().to(topic_patch))
+                    .route(web::delete().to(topic_delete)))
+                .service(web::resource("/subscribe/token")
+                    .route(web::post().to(subscribe_token)))
                 .service(web::resource("/webhooks")
                     .route(web::get().to(get_webhooks))
                     .route(web::post().to(webhooks_post)))
@@ -439,34 +1583,47 @@ async fn main() -> std::io::Result<()> {
                     .route(web::delete().to(delete_webhook_topic)))
                 .service(web::resource("/logs")
                     .route(web::get().to(get_logs)))
+                .service(web::resource("/logs/export")
+                    .route(web::get().to(export_logs)))
+                .service(web::resource("/logs/tail")
+                    .route(web::get().to(tail_logs)))
                 .service(web::resource("/api_key")
                     .route(web::get().to(get_api_key)))
                 .service(web::resource("/account")
                     .route(web::get().to(get_account))
                     .route(web::post().to(create_account)))
+                .service(web::resource("/account/log_retention")
+                    .route(web::get().to(get_log_retention))
+                    .route(web::put().to(update_log_retention)))
                 .service(web::resource("/active_devices")
                     .route(web::get().to(get_account_activity)))
             )
     })
-    // TODO: add --release flag to binary such that it can
-    // start on 0.0.0.0:8080 for release and 127.0.0.1:8080
-    // for development
-    .bind("0.0.0.0:8080")?
+    // The drain enforces the overall deadline
+    .shutdown_timeout(shutdown_timeout.as_secs());
+    let server = match tls_cert {
+        Some(cert) => server.bind_rustls(&bind, tls::server_config(cert)?)?,
+        None => server.bind(&bind)?,
+    }
     .run();
 
-    let srv = server.clone();
+    let drain = shutdown::Drain {
+        draining: shutdown_draining,
+        publisher: weak_publish_addr,
+        webhook_publisher: drain_webhook_publisher_addr,
+        log_writer: drain_log_writer_addr,
+        server: server.clone(),
+        reconnect_spread,
+        timeout: shutdown_timeout,
+    };
     ctrlc::set_handler(move || {
         /*
             On shutdown, need to send messages to websockets
             to send a shutdown request, therefore allowing
             daemons to disconnect and then reconnect with
-            new server
+            new server, spread out over reconnect_spread
         */
-        match weak_publish_addr.upgrade() {
-            Some(addr) => addr.do_send(publisher::Shutdown()),
-            None => (),
-        }
-        executor::block_on(srv.stop(true));
+        drain.run();
     }).expect("Error setting Ctrl-C handler");
 
     server.await
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix::prelude::*;
+use tracing::error;
+use prometheus::{
+    Encoder, GaugeVec, Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+use crate::db::DbPool;
+
+// How long a scrape waits on an actor's mailbox before
+// reporting it as stuck
+const MAILBOX_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+// Account and topic ids get their own series until there
+// are this many, later ones share the OTHER_LABEL series
+const MAX_LABELLED_ACCOUNTS: usize = 1000;
+const MAX_LABELLED_TOPICS: usize = 1000;
+const OTHER_LABEL: &str = "other";
+// Label of messages to topics that don't exist, their
+// names come from devices
+pub const UNKNOWN_TOPIC_LABEL: &str = "unknown";
+
+lazy_static! {
+    static ref ACCOUNT_LABELS: LabelSet = LabelSet::new(MAX_LABELLED_ACCOUNTS);
+    static ref TOPIC_LABELS: LabelSet = LabelSet::new(MAX_LABELLED_TOPICS);
+
+    pub static ref CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "websocket_connections",
+        "Active websocket connections",
+        &["account_id"]
+    ).unwrap();
+    pub static ref MESSAGES_PUBLISHED: IntCounterVec = register_int_counter_vec!(
+        "messages_published_total",
+        "Messages published to a topic",
+        &["topic_id"]
+    ).unwrap();
+    // A device is counted once per message, under the
+    // first of the message's topics it is registered to
+    pub static ref MESSAGES_DELIVERED: IntCounterVec = register_int_counter_vec!(
+        "messages_delivered_total",
+        "Messages handed to a connected device",
+        &["topic_id"]
+    ).unwrap();
+    // reason is unknown_topic or not_connected
+    pub static ref MESSAGES_DROPPED: IntCounterVec = register_int_counter_vec!(
+        "messages_dropped_total",
+        "Messages that could not be delivered",
+        &["topic_id", "reason"]
+    ).unwrap();
+    pub static ref RATE_LIMIT_REJECTIONS: IntCounterVec = register_int_counter_vec!(
+        "rate_limit_rejections_total",
+        "Device messages rejected by the rate limit",
+        &["account_id"]
+    ).unwrap();
+    pub static ref WEBHOOK_LATENCY: Histogram = register_histogram!(
+        "webhook_delivery_seconds",
+        "Webhook delivery latency"
+    ).unwrap();
+    pub static ref WEBHOOK_FAILURES: IntCounter = register_int_counter!(
+        "webhook_failures_total",
+        "Webhook deliveries that failed"
+    ).unwrap();
+    static ref DB_POOL_CONNECTIONS: IntGauge = register_int_gauge!(
+        "db_pool_connections",
+        "Connections held by the database pool"
+    ).unwrap();
+    static ref DB_POOL_IDLE_CONNECTIONS: IntGauge = register_int_gauge!(
+        "db_pool_idle_connections",
+        "Idle connections in the database pool"
+    ).unwrap();
+    static ref DB_POOL_MAX_SIZE: IntGauge = register_int_gauge!(
+        "db_pool_max_size",
+        "Maximum connections of the database pool"
+    ).unwrap();
+    pub static ref LOGS_DROPPED: IntCounter = register_int_counter!(
+        "logs_dropped_total",
+        "Logs dropped because the log writer fell behind"
+    ).unwrap();
+    // actix doesn't expose mailbox length, so report how
+    // long a message waits before the actor handles it
+    static ref MAILBOX_LATENCY: GaugeVec = register_gauge_vec!(
+        "actor_mailbox_latency_seconds",
+        "Time for a probe message to be handled by an actor",
+        &["actor"]
+    ).unwrap();
+}
+
+// Ids seen so far, up to limit. An id keeps the label it
+// first got, so a series is never split.
+struct LabelSet {
+    limit: usize,
+    ids: Mutex<HashSet<String>>,
+}
+
+impl LabelSet {
+    fn new(limit: usize) -> LabelSet {
+        LabelSet { limit, ids: Mutex::new(HashSet::new()) }
+    }
+
+    fn label<'a>(&self, id: &'a str) -> &'a str {
+        let mut ids = match self.ids.lock() {
+            Ok(ids) => ids,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if ids.contains(id) {
+            return id;
+        }
+        if ids.len() < self.limit {
+            ids.insert(id.to_string());
+            return id;
+        }
+        OTHER_LABEL
+    }
+}
+
+pub fn account_label(account_id: &str) -> &str {
+    ACCOUNT_LABELS.label(account_id)
+}
+
+pub fn topic_label(topic_id: &str) -> &str {
+    TOPIC_LABELS.label(topic_id)
+}
+
+// Handled by an actor as soon as it reaches the front
+// of the mailbox
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MailboxProbe;
+
+pub async fn probe_mailbox<A>(actor: &str, addr: &Addr<A>)
+where
+    A: Actor + Handler<MailboxProbe>,
+    A::Context: actix::dev::ToEnvelope<A, MailboxProbe>,
+{
+    let start = Instant::now();
+    let latency = match addr.send(MailboxProbe).timeout(MAILBOX_PROBE_TIMEOUT).await {
+        Ok(_) => start.elapsed().as_secs_f64(),
+        Err(_) => MAILBOX_PROBE_TIMEOUT.as_secs_f64(),
+    };
+    MAILBOX_LATENCY.with_label_values(&[actor]).set(latency);
+}
+
+// Metrics in the Prometheus text format, gauges that are
+// sampled rather than updated as things happen are read now
+pub fn render(pool: &DbPool) -> (String, Vec<u8>) {
+    let state = pool.state();
+    DB_POOL_CONNECTIONS.set(state.connections as i64);
+    DB_POOL_IDLE_CONNECTIONS.set(state.idle_connections as i64);
+    DB_POOL_MAX_SIZE.set(pool.max_size() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!(error = ?e, "Error encoding metrics");
+    }
+    (encoder.format_type().to_string(), buffer)
+}
@@ -5,6 +5,8 @@ use super::schema::webhooks;
 use super::schema::webhook_topics;
 use super::schema::logs;
 use super::schema::accounts;
+use super::schema::device_shadows;
+use super::schema::device_sessions;
 
 use std::time::SystemTime;
 use serde::{Serialize};
@@ -29,21 +31,85 @@ pub struct NewDeviceType<'a> {
     pub description: Option<&'a str>,
 }
 
+#[derive(AsChangeset)]
+#[table_name = "device_types"]
+pub struct DeviceTypeChangeset<'a> {
+    pub name: Option<&'a str>,
+    pub description: Option<&'a str>,
+}
+
 #[derive(Queryable)]
 pub struct Devices {
     pub id: String,
     pub device_type_id: String,
     pub created_at: SystemTime,
     pub updated_at: SystemTime,
+    pub metadata: Value,
+    pub tags: Vec<String>,
+    pub account_id: String,
 }
 
 #[derive(Insertable, Debug)]
 #[table_name = "devices"]
 pub struct NewDevice<'a> {
     pub id: &'a str,
+    pub account_id: &'a str,
     pub device_type_id: &'a str,
 }
 
+#[derive(AsChangeset)]
+#[table_name = "devices"]
+pub struct DeviceChangeset {
+    pub metadata: Option<Value>,
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(Queryable)]
+pub struct DeviceShadow {
+    pub account_id: String,
+    pub device_id: String,
+    pub reported: Value,
+    pub desired: Value,
+    pub version: i64,
+    pub created_at: SystemTime,
+    pub updated_at: SystemTime,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "device_shadows"]
+pub struct NewDeviceShadow<'a> {
+    pub account_id: &'a str,
+    pub device_id: &'a str,
+    pub reported: Value,
+    pub desired: Value,
+    pub version: i64,
+}
+
+#[derive(Queryable)]
+pub struct DeviceSession {
+    pub id: String,
+    pub account_id: String,
+    pub device_id: String,
+    pub device_type_id: String,
+    pub peer_address: Option<String>,
+    pub connected_at: SystemTime,
+    pub disconnected_at: Option<SystemTime>,
+    pub close_reason: Option<String>,
+    pub messages_in: i64,
+    pub messages_out: i64,
+    pub last_seen_at: SystemTime,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "device_sessions"]
+pub struct NewDeviceSession<'a> {
+    pub id: &'a str,
+    pub account_id: &'a str,
+    pub device_id: &'a str,
+    pub device_type_id: &'a str,
+    pub peer_address: Option<&'a str>,
+}
+
 #[derive(Queryable)]
 pub struct Topic {
     pub id: String,
@@ -63,6 +129,13 @@ pub struct NewTopic<'a> {
     pub description: Option<&'a str>,
 }
 
+#[derive(AsChangeset)]
+#[table_name = "topics"]
+pub struct TopicChangeset<'a> {
+    pub name: Option<&'a str>,
+    pub description: Option<&'a str>,
+}
+
 #[derive(Queryable)]
 pub struct Webhook {
     pub id: i32,
@@ -122,6 +195,9 @@ pub struct Account {
     pub updated_at: SystemTime,
     pub max_requests_per_minute: i32,
     pub max_connections: i32,
+    pub suspended: bool,
+    pub log_retention_days: Option<i32>,
+    pub log_retention_max_rows: Option<i32>,
 }
 
 #[derive(Insertable, Debug)]
@@ -130,4 +206,6 @@ pub struct NewAccount<'a> {
     pub id: &'a str,
     pub secret_key: &'a str,
     pub cipher_iv: &'a str,
+    pub max_requests_per_minute: i32,
+    pub max_connections: i32,
 }
@@ -1,8 +1,13 @@
+use std::time::{Duration, SystemTime};
 use diesel::prelude::*;
 use diesel::query_dsl::methods::LoadQuery;
 use diesel::query_builder::*;
 use diesel::pg::Pg;
 use diesel::sql_types::BigInt;
+use data_encoding::BASE64URL_NOPAD;
+use serde::Serialize;
+
+use crate::models;
 
 pub trait Paginate: Sized {
     fn paginate(self, page: i64) -> Paginated<Self>;
@@ -66,3 +71,160 @@ where
         Ok(())
     }
 }
+
+// Keyset pagination. Results are ordered by created_at and id
+// descending and each page starts after the last item of the
+// previous one, so pages don't drift as new rows are added and
+// stay fast no matter how deep the client pages.
+#[derive(Debug, Clone, Copy, QueryId)]
+pub struct CursorPaginated<T> {
+    query: T,
+    per_page: i64,
+    // One more than per_page, to know if there's another page
+    limit: i64,
+}
+
+pub trait CursorPaginate: Sized {
+    fn paginate_after(self, per_page: i64) -> CursorPaginated<Self>;
+}
+
+impl<T> CursorPaginate for T {
+    fn paginate_after(self, per_page: i64) -> CursorPaginated<Self> {
+        CursorPaginated {
+            query: self,
+            per_page,
+            limit: per_page + 1,
+        }
+    }
+}
+
+// Position of an item in a keyset ordered list
+#[derive(Debug, Clone)]
+pub struct Cursor {
+    pub created_at: SystemTime,
+    pub id: String,
+}
+
+impl Cursor {
+    // Opaque string handed to clients as next_cursor
+    pub fn encode(&self) -> String {
+        let micros = self.created_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_micros())
+            .unwrap_or(0);
+        BASE64URL_NOPAD.encode(format!("{}:{}", micros, self.id).as_bytes())
+    }
+
+    pub fn decode(cursor: &str) -> Option<Cursor> {
+        let bytes = BASE64URL_NOPAD.decode(cursor.as_bytes()).ok()?;
+        let decoded = String::from_utf8(bytes).ok()?;
+        let mut parts = decoded.splitn(2, ':');
+        let micros: u64 = parts.next()?.parse().ok()?;
+        let id = parts.next()?.to_string();
+        Some(Cursor {
+            created_at: SystemTime::UNIX_EPOCH + Duration::from_micros(micros),
+            id,
+        })
+    }
+
+    // For tables with serial ids
+    pub fn int_id(&self) -> QueryResult<i32> {
+        self.id.parse().map_err(|_| invalid_cursor())
+    }
+}
+
+pub fn invalid_cursor() -> diesel::result::Error {
+    diesel::result::Error::QueryBuilderError("invalid cursor".into())
+}
+
+// Rows that can be paged through with a Cursor
+pub trait Keyset {
+    fn cursor(&self) -> Cursor;
+}
+
+#[derive(Debug, Serialize)]
+pub struct CursorPage<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+impl<T> CursorPage<T> {
+    pub fn map<U, F: FnMut(T) -> U>(self, f: F) -> CursorPage<U> {
+        CursorPage {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+        }
+    }
+}
+
+impl<T> CursorPaginated<T> {
+    pub fn load_page<U: Keyset>(self, conn: &PgConnection) -> QueryResult<CursorPage<U>>
+    where
+        Self: LoadQuery<PgConnection, U>,
+    {
+        let per_page = self.per_page as usize;
+        let mut items = self.load::<U>(conn)?;
+        let next_cursor = match items.len() > per_page {
+            true => {
+                items.truncate(per_page);
+                items.last().map(|item| item.cursor().encode())
+            },
+            false => None,
+        };
+        Ok(CursorPage { items, next_cursor })
+    }
+}
+
+impl<T: Query> Query for CursorPaginated<T> {
+    type SqlType = T::SqlType;
+}
+
+impl<T> RunQueryDsl<PgConnection> for CursorPaginated<T> {}
+
+impl<T> QueryFragment<Pg> for CursorPaginated<T>
+where
+    T: QueryFragment<Pg>,
+{
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        self.query.walk_ast(out.reborrow())?;
+        out.push_sql(" LIMIT ");
+        out.push_bind_param::<BigInt, _>(&self.limit)?;
+        Ok(())
+    }
+}
+
+impl Keyset for models::Log {
+    fn cursor(&self) -> Cursor {
+        Cursor { created_at: self.created_at, id: self.id.to_string() }
+    }
+}
+
+impl Keyset for models::DeviceType {
+    fn cursor(&self) -> Cursor {
+        Cursor { created_at: self.created_at, id: self.id.clone() }
+    }
+}
+
+impl Keyset for models::Devices {
+    fn cursor(&self) -> Cursor {
+        Cursor { created_at: self.created_at, id: self.id.clone() }
+    }
+}
+
+impl Keyset for models::DeviceSession {
+    fn cursor(&self) -> Cursor {
+        Cursor { created_at: self.connected_at, id: self.id.clone() }
+    }
+}
+
+impl Keyset for models::Topic {
+    fn cursor(&self) -> Cursor {
+        Cursor { created_at: self.created_at, id: self.id.clone() }
+    }
+}
+
+impl Keyset for models::Webhook {
+    fn cursor(&self) -> Cursor {
+        Cursor { created_at: self.created_at, id: self.id.to_string() }
+    }
+}
@@ -5,18 +5,26 @@ use serde::{Serialize};
 use std::net::SocketAddr;
 use serde_json::json;
 use serde_json::Value;
+use tracing::{debug, error, info, info_span, warn};
+use actix_web_actors::ws;
+use actix_web::web::Bytes;
+use futures::channel::mpsc;
+use rand::Rng;
 
 use crate::websocket::Message;
 
 use crate::websocket::{WebSocket};
 use crate::webhook_publisher::{WebhookPublisher};
+use crate::log_writer::{LogWriter};
 
 use crate::db;
 use crate::db::DbPool;
 use crate::logging;
 use crate::account;
-
-const TOPIC_RELATIONS_UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+use crate::utils;
+use crate::shadow::ShadowEvent;
+use crate::metrics;
+use crate::sse;
 
 #[derive(Serialize, Clone)]
 pub enum Sender {
@@ -24,7 +32,10 @@ pub enum Sender {
     Device {
         device_id: String,
         device_type_id: String,
-    }
+    },
+    // Messages generated by the server itself,
+    // e.g. presence events
+    System,
 }
 
 #[derive(Message, Serialize, Clone)]
@@ -49,24 +60,75 @@ pub struct Connect {
     pub account_id: String,
     pub device_type_id: String,
     pub device_id: String,
+    // Unique per WebSocket, used to tell a device's
+    // connections apart when it reconnects
+    pub session_id: String,
+    pub will: Option<Will>,
     pub addr: Addr<WebSocket>
 }
 
+// Message published on behalf of a device when its
+// connection ends without a clean close
+#[derive(Clone, Debug)]
+pub struct Will {
+    pub topic: String,
+    pub data: Value,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum DisconnectReason {
+    // The device sent a Close frame
+    ClientClose,
+    // The device stopped answering pings
+    HeartbeatTimeout,
+    // The server closed the connection, e.g. when the
+    // account was suspended or the device reconnected
+    ServerClose,
+    // The server is shutting down or restarting
+    ServerShutdown,
+    // The account was already at max_connections
+    MaxConnections,
+    // The connection ended without a Close frame
+    ConnectionLost,
+    // The server stopped without closing the session,
+    // set when the next server starts
+    ServerRestart,
+}
+
+impl DisconnectReason {
+    // Stored as the close_reason of device sessions
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::ClientClose => "client_close",
+            DisconnectReason::HeartbeatTimeout => "heartbeat_timeout",
+            DisconnectReason::ServerClose => "server_close",
+            DisconnectReason::ServerShutdown => "server_shutdown",
+            DisconnectReason::MaxConnections => "max_connections",
+            DisconnectReason::ConnectionLost => "connection_lost",
+            DisconnectReason::ServerRestart => "server_restart",
+        }
+    }
+}
+
+pub const MAX_CONNECTIONS_ERROR: &str = "Exceeded number of connections";
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Disconnect{
     pub account_id: String,
     pub device_type_id: String,
     pub device_id: String,
+    pub session_id: String,
+    pub reason: DisconnectReason,
 }
 
 #[derive(Hash, Eq, PartialEq, Clone, Serialize)]
 pub struct Device {
-    device_id: String,
+    pub device_id: String,
     // Including device_type_id is not strictly necessary,
     // adding it here just saves a query in the future,
     // but maybe that should be done now?
-    device_type_id: String,
+    pub device_type_id: String,
 }
 
 #[derive(Clone)]
@@ -79,38 +141,179 @@ pub struct Account {
 
 // When a server is being taken down and another
 // put up, need to close the websocket connections
-// and tell them to reopen
+// and tell them to reopen. Each device gets a random
+// delay within reconnect_spread so they don't all
+// reconnect to the new server at once.
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct Shutdown();
+pub struct Shutdown {
+    pub reconnect_spread: Duration,
+}
+
+// Sent to a WebSocket on shutdown, the device should
+// wait reconnect_after before reconnecting
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Reconnect {
+    pub reconnect_after: Duration,
+}
+
+// Number of open device sessions, polled while draining
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct SessionCount();
 
 #[derive(Message)]
 #[rtype(result = "Option<HashSet<Device>>")]
 pub struct GetAccountActivity(pub String);
 
+// Sent to a WebSocket to close it with the given reason
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseSession {
+    pub code: ws::CloseCode,
+    pub description: String,
+}
+
+// Sent to a WebSocket when the account's rate limit changes
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UpdateRateLimit(pub i32);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UpdateAccountLimits {
+    pub account_id: String,
+    pub max_requests_per_minute: i32,
+    pub max_connections: i32,
+}
+
+// Sent after a topic is created or renamed so the
+// publisher picks it up without waiting for a refresh
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UpsertTopic {
+    pub account_id: String,
+    pub topic_id: String,
+    pub name: String,
+}
+
+// Sent after a topic is deleted, forgets every device
+// registration and webhook for the topic
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct RemoveTopic {
+    pub account_id: String,
+    pub topic_id: String,
+}
+
+// Close the live connection of a single device,
+// used when a device is deleted
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DisconnectDevice {
+    pub account_id: String,
+    pub device_id: String,
+    pub reason: String,
+}
+
+// Forward a shadow event to a device if it's connected
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SendShadowEvent {
+    pub account_id: String,
+    pub device_id: String,
+    pub event: ShadowEvent,
+}
+
+// Close every live connection of an account, used when
+// an account is suspended or deleted
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DisconnectAccount {
+    pub account_id: String,
+    pub reason: String,
+    // Also forget the account's topics, set when the
+    // account has been deleted
+    pub deleted: bool,
+}
+
+struct Session {
+    id: String,
+    will: Option<Will>,
+    addr: Addr<WebSocket>,
+}
+
+// Start sending the messages published to an account's
+// topics, as server-sent events, to sender. Topics may be
+// ids or names, returns the subscribed topic ids.
+#[derive(Message)]
+#[rtype(result = "Result<Vec<String>, SubscribeError>")]
+pub struct Subscribe {
+    pub account_id: String,
+    pub topics: Vec<String>,
+    pub sender: mpsc::Sender<Bytes>,
+}
+
+pub enum SubscribeError {
+    // The first topic the account doesn't have
+    UnknownTopic(String),
+    // The account already has max_subscribers open
+    TooManySubscribers,
+}
+
+// A browser or other client receiving messages over
+// server-sent events. Subscribers aren't devices, they
+// can't publish and don't count against max_connections.
+struct Subscriber {
+    account_id: String,
+    topics: HashSet<String>,
+}
+
 pub struct Publisher {
     pool: DbPool,
     webhook_publisher: Addr<WebhookPublisher>,
-    // device_ids to WebSocket address
-    sessions: HashMap<String, Addr<WebSocket>>,
-    // topic_id to HashSet of device_ids
+    log_writer: Addr<LogWriter>,
+    // (account_id, device_id) to WebSocket address. Device ids
+    // are chosen by customers and only unique within an account.
+    sessions: HashMap<(String, String), Session>,
+    // topic_id to HashSet of device_ids, all devices of a
+    // topic belong to the account owning the topic
     topics: HashMap<String, HashSet<String>>,
     // account_id to HashSet of topic_ids
     topic_relations: HashMap<String, HashSet<String>>,
+    // account_id to topic name to topic_id
+    topic_names: HashMap<String, HashMap<String, String>>,
     // active account connections
     // account_id -> Account
     accounts: HashMap<String, Account>,
+    subscribers: sse::Clients<Subscriber>,
+    // Subscribers per account, they skip max_connections
+    // so a token can't open streams without limit
+    max_subscribers: usize,
+    topic_refresh_interval: Duration,
 }
 
 impl Publisher {
-    pub fn initialize(pool: DbPool, webhook_publisher: Addr<WebhookPublisher>) -> Publisher {
+    pub fn initialize(
+        pool: DbPool,
+        webhook_publisher: Addr<WebhookPublisher>,
+        log_writer: Addr<LogWriter>,
+        max_subscribers: usize,
+        topic_refresh_interval: Duration,
+    ) -> Publisher {
         Publisher {
             pool,
             webhook_publisher,
+            log_writer,
             sessions: HashMap::new(),
             topics: HashMap::new(),
             topic_relations: HashMap::new(),
+            topic_names: HashMap::new(),
             accounts: HashMap::new(),
+            subscribers: sse::Clients::default(),
+            max_subscribers,
+            topic_refresh_interval,
         }
     }
 
@@ -124,25 +327,181 @@ impl Publisher {
                         return;
                     }
                 };
+                // Rebuild from scratch so renamed and
+                // deleted topics don't linger
+                let mut topic_relations: HashMap<String, HashSet<String>> = HashMap::new();
+                let mut topic_names: HashMap<String, HashMap<String, String>> = HashMap::new();
                 for item in relations {
-                    match publisher.topic_relations.get_mut(&item.account_id) {
-                        Some(set) => {
-                            set.insert(item.id);
-                        },
-                        None => {
-                            let mut set = HashSet::new();
-                            set.insert(item.id);
-                            publisher.topic_relations.insert(item.account_id.clone(), set);
-                        }
-                    }
+                    topic_relations
+                        .entry(item.account_id.clone())
+                        .or_insert_with(HashSet::new)
+                        .insert(item.id.clone());
+                    topic_names
+                        .entry(item.account_id)
+                        .or_insert_with(HashMap::new)
+                        .insert(item.name, item.id);
                 }
+                publisher.topic_relations = topic_relations;
+                publisher.topic_names = topic_names;
             },
-            Err(e) => println!("Error getting database connection: {:?}", e),
+            Err(e) => error!(error = ?e, "Error getting database connection"),
+        }
+    }
+
+    // Topics can be referenced by id or by name, returns
+    // the topic_id if the account has such a topic
+    fn resolve_topic(&self, account_id: &str, topic: &str) -> Option<String> {
+        if let Some(topics) = self.topic_relations.get(account_id) {
+            if topics.contains(topic) {
+                return Some(topic.to_string());
+            }
+        }
+        self.topic_names
+            .get(account_id)
+            .and_then(|names| names.get(topic))
+            .cloned()
+    }
+
+    // Only the server publishes presence events
+    fn is_presence_topic(&self, account_id: &str, topic_id: &str) -> bool {
+        self.topic_names
+            .get(account_id)
+            .and_then(|names| names.get(db::PRESENCE_TOPIC))
+            .map_or(false, |id| id == topic_id)
+    }
+
+    // Remove a device from every topic of its account
+    fn unregister_device(&mut self, account_id: &str, device_id: &str) {
+        let topic_ids = match self.topic_relations.get(account_id) {
+            Some(t) => t,
+            None => return,
+        };
+        for topic_id in topic_ids.iter() {
+            if let Some(devices) = self.topics.get_mut(topic_id) {
+                devices.remove(device_id);
+            }
+        }
+    }
+
+    // Close a device's session and stop routing
+    // messages to it
+    fn close_session(&mut self, account_id: &str, device_id: &str, reason: &str) {
+        let key = (account_id.to_string(), device_id.to_string());
+        if let Some(session) = self.remove_session(&key) {
+            session.addr.do_send(CloseSession {
+                code: ws::CloseCode::Policy,
+                description: reason.to_string(),
+            });
+        }
+        self.unregister_device(account_id, device_id);
+    }
+
+    fn disconnect_account(&mut self, account_id: &str, reason: &str) {
+        let account = match self.accounts.remove(account_id) {
+            Some(a) => a,
+            None => return,
+        };
+        for device in account.devices.iter() {
+            self.close_session(account_id, &device.device_id, reason);
+        }
+    }
+
+    // Dropping the sender ends the subscriber's stream
+    fn remove_subscribers(&mut self, account_id: &str) {
+        self.subscribers.retain(|subscriber| subscriber.account_id != account_id);
+    }
+
+    // Sessions are only added and removed through these
+    // two so the connections gauge follows them
+    fn insert_session(&mut self, key: (String, String), session: Session) -> Option<Session> {
+        let connections = metrics::CONNECTIONS.with_label_values(&[metrics::account_label(&key.0)]);
+        let old_session = self.sessions.insert(key, session);
+        if old_session.is_none() {
+            connections.inc();
+        }
+        old_session
+    }
+
+    fn remove_session(&mut self, key: &(String, String)) -> Option<Session> {
+        let session = self.sessions.remove(key);
+        if session.is_some() {
+            metrics::CONNECTIONS.with_label_values(&[metrics::account_label(&key.0)]).dec();
+        }
+        session
+    }
+
+    // Id of the account's presence topic, created
+    // on first use
+    fn presence_topic_id(&mut self, account_id: &str) -> Option<String> {
+        let cached = self.topic_names
+            .get(account_id)
+            .and_then(|names| names.get(db::PRESENCE_TOPIC))
+            .cloned();
+        if cached.is_some() {
+            return cached;
         }
+
+        let conn = match self.pool.get() {
+            Ok(c) => c,
+            Err(e) => {
+                error!(error = ?e, "Error getting database connection");
+                return None;
+            },
+        };
+        let topic_id = match db::get_or_create_presence_topic(account_id, &conn) {
+            Ok(id) => id,
+            Err(e) => {
+                error!(error = ?e, account_id = %account_id, "Error creating presence topic");
+                return None;
+            },
+        };
+        self.topic_relations
+            .entry(account_id.to_string())
+            .or_insert_with(HashSet::new)
+            .insert(topic_id.clone());
+        self.topic_names
+            .entry(account_id.to_string())
+            .or_insert_with(HashMap::new)
+            .insert(db::PRESENCE_TOPIC.to_string(), topic_id.clone());
+        Some(topic_id)
+    }
+
+    // Publish a presence change of a device to the
+    // account's presence topic
+    fn publish_presence(
+        &mut self,
+        account_id: &str,
+        device_id: &str,
+        device_type_id: &str,
+        event: &str,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let topic_id = match self.presence_topic_id(account_id) {
+            Some(id) => id,
+            None => return,
+        };
+        let time = match utils::get_time() {
+            Ok(t) => t,
+            Err(_) => return,
+        };
+        ctx.notify(PublishMessage {
+            sender: Sender::System,
+            account_id: account_id.to_string(),
+            message: Message {
+                seconds_since_unix: time.seconds_since_unix,
+                nano_seconds: time.nano_seconds,
+                topics: vec![topic_id],
+                data: json!({
+                    "event": event,
+                    "device_id": device_id,
+                    "device_type_id": device_type_id,
+                }),
+            },
+        });
     }
 
     fn topic_relations_refresh_interval(&self, ctx: &mut <Self as Actor>::Context) {
-        ctx.run_interval(TOPIC_RELATIONS_UPDATE_INTERVAL, |act, _ctx| {
+        ctx.run_interval(self.topic_refresh_interval, |act, _ctx| {
             Publisher::topic_relations_refresh(act);
         });
     }
@@ -152,23 +511,38 @@ impl Actor for Publisher {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
-        println!("Publisher started. Waiting for connections.");
+        info!("Publisher started, waiting for connections");
         Publisher::topic_relations_refresh(self);
         self.topic_relations_refresh_interval(ctx);
+        ctx.run_interval(sse::KEEP_ALIVE_INTERVAL, |act, _ctx| {
+            act.subscribers.keep_alive();
+        });
     }
 
     fn stopped(&mut self, _ctx: &mut Context<Self>) {
-        println!("Publisher stopped.");
+        info!("Publisher stopped");
     }
 }
 
 impl Handler<Connect> for Publisher {
     type Result = Result<(), &'static str>;
 
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) -> Self::Result {
+        let span = info_span!(
+            "connect",
+            account_id = %msg.account_id,
+            device_id = %msg.device_id,
+            session_id = %msg.session_id,
+        );
+        let _enter = span.enter();
+        let key = (msg.account_id.clone(), msg.device_id.clone());
+        // A device reconnecting before its old socket timed out
+        // takes over the old connection's slot
+        let reconnecting = self.sessions.contains_key(&key);
+
         match self.accounts.get_mut(&msg.account_id) {
             Some(account) => {
-                if account.devices.len() >= account.max_connections {
+                if !reconnecting && account.devices.len() >= account.max_connections {
                     logging::log(
                         &msg.account_id,
                         logging::LogLevel::Error,
@@ -177,10 +551,19 @@ impl Handler<Connect> for Publisher {
                             "device_type_id": msg.device_type_id,
                             "message": "max connections error"
                         }),
-                        &self.pool
+                        &self.log_writer
+                    );
+                    self.publish_presence(
+                        &msg.account_id,
+                        &msg.device_id,
+                        &msg.device_type_id,
+                        "max_connections",
+                        ctx,
                     );
-                    return Err("Exceeded number of connections");
+                    return Err(MAX_CONNECTIONS_ERROR);
                 }
+                let device_id = msg.device_id.clone();
+                account.devices.retain(|device| device.device_id != device_id);
                 account.devices.insert(Device {
                     device_id: msg.device_id.clone(),
                     device_type_id: msg.device_type_id.clone(),
@@ -199,7 +582,7 @@ impl Handler<Connect> for Publisher {
                                 "device_type_id": msg.device_type_id,
                                 "message": "connection error"
                             }),
-                            &self.pool
+                            &self.log_writer
                         );
                         return Err("Unable to get connection");
                     },
@@ -220,7 +603,7 @@ impl Handler<Connect> for Publisher {
                                 "device_type_id": msg.device_type_id,
                                 "message": "connection error"
                             }),
-                            &self.pool
+                            &self.log_writer
                         );
                         return Err("Unable to fetch account");
                     },
@@ -241,7 +624,34 @@ impl Handler<Connect> for Publisher {
                 );
             },
         }
-        self.sessions.insert(msg.device_id.clone(), msg.addr);
+        let session = Session {
+            id: msg.session_id.clone(),
+            will: msg.will.clone(),
+            addr: msg.addr,
+        };
+        if let Some(old_session) = self.insert_session(key, session) {
+            // The newest connection wins, explicitly close
+            // the old one so the device doesn't keep two
+            old_session.addr.do_send(CloseSession {
+                code: ws::CloseCode::Policy,
+                description: "replaced by a newer connection".to_string(),
+            });
+            // Registrations are keyed by device_id, the new
+            // connection registers its own topics
+            self.unregister_device(&msg.account_id, &msg.device_id);
+            logging::log(
+                &msg.account_id,
+                logging::LogLevel::Info,
+                json!({
+                    "device_id": msg.device_id,
+                    "device_type_id": msg.device_type_id,
+                    "session_id": old_session.id,
+                    "message": "replaced by a newer connection"
+                }),
+                &self.log_writer
+            );
+            self.publish_presence(&msg.account_id, &msg.device_id, &msg.device_type_id, "replaced", ctx);
+        }
 
         logging::log(
             &msg.account_id,
@@ -251,8 +661,9 @@ impl Handler<Connect> for Publisher {
                 "device_type_id": msg.device_type_id,
                 "message": "connected"
             }),
-            &self.pool
+            &self.log_writer
         );
+        self.publish_presence(&msg.account_id, &msg.device_id, &msg.device_type_id, "connected", ctx);
 
         Ok(())
     }
@@ -265,10 +676,17 @@ impl Handler<RegisterTopics> for Publisher {
         let conn = self.pool.get().expect("Failed to get a db connection");
 
         for topic in msg.topics {
-            // Check that an account can receive a topic
-            if !db::topic_relation_exists(&msg.account_id, &topic, &conn) {
-                continue;
-            }
+            // Check that an account can receive a topic,
+            // resolving topic names to their id
+            let topic_id = if topic == db::PRESENCE_TOPIC {
+                self.presence_topic_id(&msg.account_id)
+            } else {
+                db::resolve_topic_id(&msg.account_id, &topic, &conn)
+            };
+            let topic = match topic_id {
+                Some(id) => id,
+                None => continue,
+            };
             match self.topics.get_mut(&topic) {
                 // Insert device_id into existing HashSet if it exists
                 Some(v) => {
@@ -289,16 +707,35 @@ impl Handler<RegisterTopics> for Publisher {
 impl Handler<Disconnect> for Publisher {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) -> Self::Result {
+        let span = info_span!(
+            "disconnect",
+            account_id = %msg.account_id,
+            device_id = %msg.device_id,
+            session_id = %msg.session_id,
+        );
+        let _enter = span.enter();
+        let key = (msg.account_id.clone(), msg.device_id.clone());
+        // A late Disconnect from a connection that has since been
+        // replaced or closed must not tear down the live one
+        let will = match self.sessions.get(&key) {
+            Some(session) if session.id == msg.session_id => session.will.clone(),
+            _ => {
+                debug!(session_id = %msg.session_id, "Ignoring disconnect of stale session");
+                return;
+            },
+        };
+
         logging::log(
             &msg.account_id,
             logging::LogLevel::Info,
             json!({
                 "device_id": msg.device_id,
                 "device_type_id": msg.device_type_id,
-                "message": "disconnected"
+                "message": "disconnected",
+                "reason": format!("{:?}", msg.reason),
             }),
-            &self.pool
+            &self.log_writer
         );
         match self.accounts.get_mut(&msg.account_id) {
             Some(account) => {
@@ -308,51 +745,125 @@ impl Handler<Disconnect> for Publisher {
                 });
             },
             None => {
-                eprintln!(
-                    "{} closing for account {}, but no account struct in publisher.",
-                    &msg.device_id,
-                    &msg.account_id,
-                );
+                warn!("Device closing, but no account struct in publisher");
             }
         }
 
-        self.sessions.remove(&msg.device_id);
+        self.remove_session(&key);
+        self.unregister_device(&msg.account_id, &msg.device_id);
+
+        let event = match msg.reason {
+            DisconnectReason::HeartbeatTimeout => "heartbeat_timeout",
+            _ => "disconnected",
+        };
+        self.publish_presence(&msg.account_id, &msg.device_id, &msg.device_type_id, event, ctx);
+
+        // Only publish the will when the device went away
+        // without saying goodbye
+        let unexpected = match msg.reason {
+            DisconnectReason::HeartbeatTimeout => true,
+            DisconnectReason::ConnectionLost => true,
+            DisconnectReason::ClientClose => false,
+            DisconnectReason::ServerClose => false,
+            DisconnectReason::ServerShutdown => false,
+            DisconnectReason::MaxConnections => false,
+            DisconnectReason::ServerRestart => false,
+        };
+        if let (true, Some(will)) = (unexpected, will) {
+            let time = match utils::get_time() {
+                Ok(t) => t,
+                Err(_) => return,
+            };
+            ctx.notify(PublishMessage {
+                sender: Sender::Device {
+                    device_id: msg.device_id.clone(),
+                    device_type_id: msg.device_type_id.clone(),
+                },
+                account_id: msg.account_id.clone(),
+                message: Message {
+                    seconds_since_unix: time.seconds_since_unix,
+                    nano_seconds: time.nano_seconds,
+                    topics: vec![will.topic],
+                    data: will.data,
+                },
+            });
+        }
     }
 }
 
 impl Handler<Shutdown> for Publisher {
     type Result = ();
 
-    fn handle(&mut self, _msg: Shutdown, _: &mut Context<Self>) -> Self::Result {
-        println!("Publisher sending shutdown...");
-        for (device_id, addr) in self.sessions.iter() {
-            // TODO: add logging to record when shutdowns
-            // for deployment happen
-            println!("device_id: {:?}", device_id);
-            addr.do_send(Shutdown());
+    fn handle(&mut self, msg: Shutdown, _: &mut Context<Self>) -> Self::Result {
+        info!(sessions = self.sessions.len(), "Publisher sending shutdown");
+        let spread_ms = msg.reconnect_spread.as_millis() as u64;
+        let mut rng = rand::thread_rng();
+        for ((account_id, device_id), session) in self.sessions.iter() {
+            let reconnect_after = match spread_ms {
+                0 => Duration::from_millis(0),
+                _ => Duration::from_millis(rng.gen_range(0, spread_ms)),
+            };
+            debug!(
+                account_id = %account_id,
+                device_id = %device_id,
+                reconnect_after_ms = reconnect_after.as_millis() as u64,
+                "Sending shutdown"
+            );
+            session.addr.do_send(Reconnect { reconnect_after });
         }
+        // Browsers reconnect after the retry field, so
+        // subscribers are spread out the same way
+        self.subscribers.close(|_| {
+            let retry_ms = match spread_ms {
+                0 => 0,
+                _ => rng.gen_range(0, spread_ms),
+            };
+            Some(format!("retry: {}\n\n", retry_ms))
+        });
+    }
+}
+
+impl Handler<SessionCount> for Publisher {
+    type Result = usize;
+
+    fn handle(&mut self, _msg: SessionCount, _: &mut Context<Self>) -> Self::Result {
+        self.sessions.len()
     }
 }
 
 impl Handler<PublishMessage> for Publisher {
     type Result = ();
 
-    fn handle(&mut self, msg: PublishMessage, _ctx: &mut Context<Self>) -> Self::Result {
-        let mut devices: HashSet<String> = HashSet::new();
-        // Find all the actors that should receive a message
+    fn handle(&mut self, mut msg: PublishMessage, _ctx: &mut Context<Self>) -> Self::Result {
+        let span = info_span!("publish", account_id = %msg.account_id);
+        let _enter = span.enter();
+        // Only send to topics that an account has a relation
+        // with. Topics referenced by name are replaced with
+        // their id so receivers and webhooks always see ids.
+        let system = match msg.sender {
+            Sender::System => true,
+            _ => false,
+        };
+        let topic_ids: Vec<String> = msg.message.topics
+            .iter()
+            .filter_map(|topic| self.resolve_topic(&msg.account_id, topic))
+            .filter(|topic_id| system || !self.is_presence_topic(&msg.account_id, topic_id))
+            .collect();
+        let unknown_topics = msg.message.topics.len() - topic_ids.len();
+        if unknown_topics > 0 {
+            metrics::MESSAGES_DROPPED
+                .with_label_values(&[metrics::UNKNOWN_TOPIC_LABEL, "unknown_topic"])
+                .inc_by(unknown_topics as i64);
+        }
+        msg.message.topics = topic_ids;
         for topic in msg.message.topics.iter() {
-            // Only send to topics that an account has
-            // a relation with
-            match self.topic_relations.get(&msg.account_id) {
-                Some(topics) => {
-                    match topics.get(topic) {
-                        Some(_) => (),
-                        None => continue,
-                    }
-                },
-                None => continue,
-            }
+            metrics::MESSAGES_PUBLISHED.with_label_values(&[metrics::topic_label(topic)]).inc();
+        }
 
+        // device_id to the first topic that reached it
+        let mut devices: HashMap<String, String> = HashMap::new();
+        // Find all the actors that should receive a message
+        for topic in msg.message.topics.iter() {
             let topic_devices = match self.topics.get(topic) {
                 Some(d) => d,
                 None => continue,
@@ -365,51 +876,108 @@ impl Handler<PublishMessage> for Publisher {
                         continue;
                     }
                 }
-                devices.insert(device.to_owned());
+                devices.entry(device.to_owned()).or_insert_with(|| topic.to_owned());
             }
         }
         // Publish message to webhook only if the
-        // sender is a device (not from outside post)
-        if let Sender::Device { .. } = &msg.sender {
-            self.webhook_publisher.do_send(msg.clone());
+        // sender is a device or the server itself
+        // (not from outside post)
+        match &msg.sender {
+            Sender::Device { .. } => self.webhook_publisher.do_send(msg.clone()),
+            Sender::System => self.webhook_publisher.do_send(msg.clone()),
+            Sender::Address(_) => (),
         }
 
-        let sender_json: Value = match msg.sender.clone() {
-            Sender::Device { device_id, device_type_id} => json!({
+        let sender_json: Option<Value> = match msg.sender.clone() {
+            Sender::Device { device_id, device_type_id} => Some(json!({
                     "device_id": device_id,
                     "device_type_id": device_type_id
-                }),
+                })),
             Sender::Address(maybe_address) =>
                 match maybe_address {
-                    Some(address) => json!({
+                    Some(address) => Some(json!({
                         "ip": address.ip()
-                    }),
-                    None => Value::Null
+                    })),
+                    None => Some(Value::Null)
                 },
+            // Presence events are already logged
+            // as connects and disconnects
+            Sender::System => None,
         };
 
-        logging::log(
-            &msg.account_id,
-            logging::LogLevel::Info,
-            json!({
-                "sender": sender_json,
-                "data": msg.message, 
-                "message": "Message received"
-            }),
-            &self.pool
-        );
+        if let Some(sender_json) = sender_json {
+            logging::log(
+                &msg.account_id,
+                logging::LogLevel::Info,
+                json!({
+                    "sender": sender_json,
+                    "data": msg.message, 
+                    "message": "Message received"
+                }),
+                &self.log_writer
+            );
+        }
 
         // TODO: need to check if a message is allowed
         // to send to a specific topic
 
         // Publish message to other devices
-        for device in devices.iter() {
-            let addr = match self.sessions.get(device) {
+        for (device, topic) in devices.iter() {
+            let topic = metrics::topic_label(topic);
+            let session = match self.sessions.get(&(msg.account_id.clone(), device.clone())) {
                 Some(s) => s,
-                None => continue,
+                // Registered but without a live session
+                None => {
+                    metrics::MESSAGES_DROPPED.with_label_values(&[topic, "not_connected"]).inc();
+                    continue;
+                },
             };
-            addr.do_send(msg.clone());
+            metrics::MESSAGES_DELIVERED.with_label_values(&[topic]).inc();
+            session.addr.do_send(msg.clone());
         }
+
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let event = match serde_json::to_string(&msg) {
+            Ok(m) => format!("data: {}\n\n", m),
+            Err(e) => {
+                error!(error = ?e, "Error serializing message");
+                return;
+            },
+        };
+        let account_id = msg.account_id.clone();
+        let topics = msg.message.topics.clone();
+        self.subscribers.send(
+            |subscriber| subscriber.account_id == account_id
+                && topics.iter().any(|topic| subscriber.topics.contains(topic)),
+            &event,
+        );
+    }
+}
+
+impl Handler<Subscribe> for Publisher {
+    type Result = Result<Vec<String>, SubscribeError>;
+
+    fn handle(&mut self, msg: Subscribe, _ctx: &mut Context<Self>) -> Self::Result {
+        let account_id = &msg.account_id;
+        if self.subscribers.count(|subscriber| &subscriber.account_id == account_id) >= self.max_subscribers {
+            return Err(SubscribeError::TooManySubscribers);
+        }
+        let mut topics = HashSet::new();
+        for topic in msg.topics.iter() {
+            match self.resolve_topic(&msg.account_id, topic) {
+                Some(topic_id) => topics.insert(topic_id),
+                None => return Err(SubscribeError::UnknownTopic(topic.clone())),
+            };
+        }
+        let topic_ids: Vec<String> = topics.iter().cloned().collect();
+        debug!(account_id = %msg.account_id, topics = ?topic_ids, "Subscriber added");
+        self.subscribers.add(Subscriber {
+            account_id: msg.account_id,
+            topics,
+        }, msg.sender);
+        Ok(topic_ids)
     }
 }
 
@@ -423,3 +991,106 @@ impl Handler<GetAccountActivity> for Publisher {
         }
     }
 }
+
+impl Handler<UpdateAccountLimits> for Publisher {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateAccountLimits, _ctx: &mut Context<Self>) -> Self::Result {
+        let account = match self.accounts.get_mut(&msg.account_id) {
+            Some(a) => a,
+            // Limits are read from the database on the
+            // account's next connection
+            None => return,
+        };
+        account.max_connections = msg.max_connections as usize;
+        for device in account.devices.iter() {
+            if let Some(session) = self.sessions.get(&(msg.account_id.clone(), device.device_id.clone())) {
+                session.addr.do_send(UpdateRateLimit(msg.max_requests_per_minute));
+            }
+        }
+    }
+}
+
+impl Handler<DisconnectAccount> for Publisher {
+    type Result = ();
+
+    fn handle(&mut self, msg: DisconnectAccount, _ctx: &mut Context<Self>) -> Self::Result {
+        self.disconnect_account(&msg.account_id, &msg.reason);
+        self.remove_subscribers(&msg.account_id);
+        if msg.deleted {
+            if let Some(topic_ids) = self.topic_relations.remove(&msg.account_id) {
+                for topic_id in topic_ids.iter() {
+                    self.topics.remove(topic_id);
+                }
+            }
+            self.topic_names.remove(&msg.account_id);
+        }
+    }
+}
+
+impl Handler<DisconnectDevice> for Publisher {
+    type Result = ();
+
+    fn handle(&mut self, msg: DisconnectDevice, _ctx: &mut Context<Self>) -> Self::Result {
+        let account = match self.accounts.get_mut(&msg.account_id) {
+            Some(a) => a,
+            None => return,
+        };
+        let device_id = msg.device_id.clone();
+        account.devices.retain(|device| device.device_id != device_id);
+        self.close_session(&msg.account_id, &msg.device_id, &msg.reason);
+    }
+}
+
+impl Handler<UpsertTopic> for Publisher {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpsertTopic, _ctx: &mut Context<Self>) -> Self::Result {
+        self.topic_relations
+            .entry(msg.account_id.clone())
+            .or_insert_with(HashSet::new)
+            .insert(msg.topic_id.clone());
+        let names = self.topic_names
+            .entry(msg.account_id)
+            .or_insert_with(HashMap::new);
+        // Forget the old name of a renamed topic
+        let topic_id = msg.topic_id.clone();
+        names.retain(|_, id| *id != topic_id);
+        names.insert(msg.name, msg.topic_id);
+    }
+}
+
+impl Handler<RemoveTopic> for Publisher {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveTopic, _ctx: &mut Context<Self>) -> Self::Result {
+        self.topics.remove(&msg.topic_id);
+        for subscriber in self.subscribers.filters_mut() {
+            subscriber.topics.remove(&msg.topic_id);
+        }
+        if let Some(topics) = self.topic_relations.get_mut(&msg.account_id) {
+            topics.remove(&msg.topic_id);
+        }
+        if let Some(names) = self.topic_names.get_mut(&msg.account_id) {
+            let topic_id = msg.topic_id.clone();
+            names.retain(|_, id| *id != topic_id);
+        }
+        self.webhook_publisher.do_send(msg);
+    }
+}
+
+impl Handler<SendShadowEvent> for Publisher {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendShadowEvent, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(session) = self.sessions.get(&(msg.account_id, msg.device_id)) {
+            session.addr.do_send(msg.event);
+        }
+    }
+}
+
+impl Handler<metrics::MailboxProbe> for Publisher {
+    type Result = ();
+
+    fn handle(&mut self, _msg: metrics::MailboxProbe, _ctx: &mut Context<Self>) -> Self::Result {}
+}
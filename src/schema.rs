@@ -7,6 +7,45 @@ table! {
         updated_at -> Timestamp,
         max_requests_per_minute -> Int4,
         max_connections -> Int4,
+        suspended -> Bool,
+        log_retention_days -> Nullable<Int4>,
+        log_retention_max_rows -> Nullable<Int4>,
+    }
+}
+
+table! {
+    deleted_devices (account_id, id) {
+        account_id -> Varchar,
+        id -> Varchar,
+        deleted_at -> Timestamp,
+    }
+}
+
+table! {
+    device_sessions (id) {
+        id -> Varchar,
+        account_id -> Varchar,
+        device_id -> Varchar,
+        device_type_id -> Varchar,
+        peer_address -> Nullable<Varchar>,
+        connected_at -> Timestamp,
+        disconnected_at -> Nullable<Timestamp>,
+        close_reason -> Nullable<Varchar>,
+        messages_in -> Int8,
+        messages_out -> Int8,
+        last_seen_at -> Timestamp,
+    }
+}
+
+table! {
+    device_shadows (account_id, device_id) {
+        account_id -> Varchar,
+        device_id -> Varchar,
+        reported -> Jsonb,
+        desired -> Jsonb,
+        version -> Int8,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
     }
 }
 
@@ -22,11 +61,14 @@ table! {
 }
 
 table! {
-    devices (id) {
+    devices (account_id, id) {
         id -> Varchar,
         device_type_id -> Varchar,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        metadata -> Jsonb,
+        tags -> Array<Text>,
+        account_id -> Varchar,
     }
 }
 
@@ -78,6 +120,9 @@ joinable!(webhook_topics -> webhooks (webhook_id));
 
 allow_tables_to_appear_in_same_query!(
     accounts,
+    deleted_devices,
+    device_sessions,
+    device_shadows,
     device_types,
     devices,
     logs,
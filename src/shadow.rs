@@ -0,0 +1,237 @@
+use std::fmt;
+use actix::prelude::*;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::models;
+use crate::utils::{instant_to_seconds};
+
+// A device shadow holds the state a device last reported
+// and the state it is asked to move to. Every change bumps
+// the version, writers can pass the version they last saw
+// to avoid overwriting a change they haven't seen.
+#[derive(Debug, Serialize)]
+pub struct Shadow {
+    pub device_id: String,
+    pub reported: Value,
+    pub desired: Value,
+    pub delta: Value,
+    pub version: i64,
+    pub updated_at: Option<u64>,
+}
+
+impl From<models::DeviceShadow> for Shadow {
+    fn from(shadow: models::DeviceShadow) -> Self {
+        Shadow {
+            delta: delta(&shadow.desired, &shadow.reported),
+            device_id: shadow.device_id,
+            reported: shadow.reported,
+            desired: shadow.desired,
+            version: shadow.version,
+            updated_at: Some(instant_to_seconds(shadow.updated_at)),
+        }
+    }
+}
+
+impl Shadow {
+    pub fn has_delta(&self) -> bool {
+        match &self.delta {
+            Value::Object(map) => !map.is_empty(),
+            _ => true,
+        }
+    }
+}
+
+pub enum ShadowDocument {
+    Reported,
+    Desired,
+}
+
+#[derive(Debug)]
+pub enum ShadowError {
+    // The version passed by the writer is not the
+    // current version, which is returned
+    VersionConflict(i64),
+    Database(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for ShadowError {
+    fn from(error: diesel::result::Error) -> Self {
+        ShadowError::Database(error)
+    }
+}
+
+impl fmt::Display for ShadowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShadowError::VersionConflict(version) => write!(f, "version conflict, current version is {}", version),
+            ShadowError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// Sent to a device's WebSocket
+#[derive(Message, Serialize, Clone, Debug)]
+#[rtype(result = "()")]
+pub enum ShadowEvent {
+    // Parts of the desired state that differ
+    // from the reported state
+    ShadowDelta {
+        version: i64,
+        state: Value,
+    },
+    ShadowRejected {
+        version: i64,
+        reason: String,
+    },
+}
+
+// Apply a JSON merge patch (RFC 7386), null values
+// remove keys
+fn merge(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(p) => p,
+        _ => {
+            *target = patch.clone();
+            return;
+        },
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+fn diff(desired: &Value, reported: &Value) -> Option<Value> {
+    match (desired, reported) {
+        (Value::Object(desired), Value::Object(reported)) => {
+            let mut result = Map::new();
+            for (key, value) in desired {
+                let changed = match reported.get(key) {
+                    Some(r) => diff(value, r),
+                    None => Some(value.clone()),
+                };
+                if let Some(c) = changed {
+                    result.insert(key.clone(), c);
+                }
+            }
+            match result.is_empty() {
+                true => None,
+                false => Some(Value::Object(result)),
+            }
+        },
+        _ => match desired == reported {
+            true => None,
+            false => Some(desired.clone()),
+        },
+    }
+}
+
+// Parts of the desired state that differ from the
+// reported state, an empty object if there are none
+pub fn delta(desired: &Value, reported: &Value) -> Value {
+    match diff(desired, reported) {
+        Some(d) => d,
+        None => Value::Object(Map::new()),
+    }
+}
+
+pub fn get_shadow<'a>(
+    account_id: &'a str,
+    device_id: &'a str,
+    conn: &PgConnection,
+) -> Result<Shadow, diesel::result::Error> {
+    use crate::schema::device_shadows::dsl;
+
+    let result = dsl::device_shadows
+        .filter(dsl::account_id.eq(account_id))
+        .filter(dsl::device_id.eq(device_id))
+        .first::<models::DeviceShadow>(conn)
+        .optional()?;
+
+    match result {
+        Some(shadow) => Ok(Shadow::from(shadow)),
+        // Devices without a shadow yet have
+        // empty documents
+        None => Ok(Shadow {
+            device_id: device_id.to_string(),
+            reported: json!({}),
+            desired: json!({}),
+            delta: json!({}),
+            version: 0,
+            updated_at: None,
+        }),
+    }
+}
+
+pub fn update_shadow<'a>(
+    account_id: &'a str,
+    device_id: &'a str,
+    document: ShadowDocument,
+    patch: &'a Value,
+    expected_version: Option<i64>,
+    conn: &PgConnection,
+) -> Result<Shadow, ShadowError> {
+    use crate::schema::device_shadows;
+    use crate::schema::device_shadows::dsl;
+
+    conn.transaction::<_, ShadowError, _>(|| {
+        // FOR UPDATE locks nothing while the device has no
+        // shadow, so an empty one is created first. A
+        // concurrent first write waits on this row instead of
+        // also starting from version 0.
+        diesel::insert_into(device_shadows::table)
+            .values(&models::NewDeviceShadow {
+                account_id,
+                device_id,
+                reported: json!({}),
+                desired: json!({}),
+                version: 0,
+            })
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        let current = dsl::device_shadows
+            .filter(dsl::account_id.eq(account_id))
+            .filter(dsl::device_id.eq(device_id))
+            .for_update()
+            .first::<models::DeviceShadow>(conn)?;
+
+        let version = current.version;
+        let mut reported = current.reported;
+        let mut desired = current.desired;
+
+        if let Some(expected) = expected_version {
+            if expected != version {
+                return Err(ShadowError::VersionConflict(version));
+            }
+        }
+
+        match document {
+            ShadowDocument::Reported => merge(&mut reported, patch),
+            ShadowDocument::Desired => merge(&mut desired, patch),
+        }
+
+        let shadow = diesel::update(dsl::device_shadows
+            .filter(dsl::account_id.eq(account_id))
+            .filter(dsl::device_id.eq(device_id)))
+            .set((
+                dsl::reported.eq(reported),
+                dsl::desired.eq(desired),
+                dsl::version.eq(version + 1),
+            ))
+            .get_result::<models::DeviceShadow>(conn)?;
+
+        Ok(Shadow::from(shadow))
+    })
+}
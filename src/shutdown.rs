@@ -0,0 +1,121 @@
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use actix::prelude::*;
+use actix::WeakAddr;
+use actix_web::dev::Server;
+use futures::executor;
+use tracing::{info, warn};
+
+use crate::publisher::{Publisher, SessionCount, Shutdown};
+use crate::webhook_publisher::{InFlightDeliveries, WebhookPublisher};
+use crate::log_writer::{CloseTails, LogWriter};
+
+// How often the drain checks whether sessions have
+// closed and webhooks have been delivered
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Set once a shutdown starts, from then on new websocket
+// handshakes are refused and the server reports not ready
+#[derive(Clone, Default)]
+pub struct Draining(Arc<AtomicBool>);
+
+impl Draining {
+    pub fn is_draining(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // Returns whether a drain had already started
+    fn start(&self) -> bool {
+        self.0.swap(true, Ordering::SeqCst)
+    }
+}
+
+// Staged shutdown run from the signal handler:
+// stop accepting devices, close their sessions with a
+// reconnect hint, wait for webhook deliveries and log
+// writes, then stop the server. Exits the process if
+// that doesn't finish within timeout.
+pub struct Drain {
+    pub draining: Draining,
+    pub publisher: WeakAddr<Publisher>,
+    pub webhook_publisher: Addr<WebhookPublisher>,
+    pub log_writer: Addr<LogWriter>,
+    pub server: Server,
+    pub reconnect_spread: Duration,
+    pub timeout: Duration,
+}
+
+impl Drain {
+    // Returns right away, the drain runs on its own threads.
+    // The signal handler runs on a single thread, so it has
+    // to return for a second signal to be handled.
+    pub fn run(&self) {
+        if self.draining.start() {
+            warn!("Shutdown already in progress, exiting now");
+            process::exit(1);
+        }
+        info!(timeout_secs = self.timeout.as_secs(), "Draining before shutdown");
+        let deadline = Instant::now() + self.timeout;
+
+        // The stages wait on actors that may be stuck, so
+        // they run on their own thread and are abandoned
+        // at the deadline
+        let (done_tx, done_rx) = mpsc::channel();
+        // WeakAddr isn't Clone, the thread gets the address
+        // the publisher has while it is still running
+        let publisher = self.publisher.upgrade();
+        let webhook_publisher = self.webhook_publisher.clone();
+        let log_writer = self.log_writer.clone();
+        let server = self.server.clone();
+        let reconnect_spread = self.reconnect_spread;
+        thread::spawn(move || {
+            if let Some(publisher) = publisher {
+                publisher.do_send(Shutdown { reconnect_spread });
+                wait_for("device sessions", deadline, || {
+                    executor::block_on(publisher.send(SessionCount())).unwrap_or(0)
+                });
+            }
+            wait_for("webhook deliveries", deadline, || {
+                executor::block_on(webhook_publisher.send(InFlightDeliveries())).unwrap_or(0)
+            });
+            // Logs of the closed sessions are already queued
+            // ahead of this. Open tails would otherwise keep
+            // the server from stopping.
+            if executor::block_on(log_writer.send(CloseTails())).is_err() {
+                warn!("Log writer stopped before flushing");
+            }
+            info!("Drain finished, stopping server");
+            executor::block_on(server.stop(true));
+            let _ = done_tx.send(());
+        });
+
+        thread::spawn(move || {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if done_rx.recv_timeout(remaining).is_err() {
+                warn!("Shutdown deadline reached, exiting");
+                process::exit(1);
+            }
+        });
+    }
+}
+
+// Poll remaining until it reaches zero or the
+// deadline passes
+fn wait_for<F: Fn() -> usize>(what: &str, deadline: Instant, remaining: F) {
+    loop {
+        let count = remaining();
+        if count == 0 {
+            info!(what, "Drained");
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!(what, count, "Deadline reached before draining");
+            return;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
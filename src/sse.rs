@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpResponse};
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+use uuid::Uuid;
+
+// Keeps idle streams from being closed by proxies, and
+// notices clients that went away
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+// Events buffered per client, a client that falls further
+// behind misses events rather than holding up the actor
+pub const BUFFER_SIZE: usize = 100;
+
+pub fn channel() -> (mpsc::Sender<Bytes>, mpsc::Receiver<Bytes>) {
+    mpsc::channel(BUFFER_SIZE)
+}
+
+pub fn response(receiver: mpsc::Receiver<Bytes>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(receiver.map(Ok::<_, Error>))
+}
+
+struct Client<T> {
+    filter: T,
+    sender: mpsc::Sender<Bytes>,
+}
+
+// Server-sent event streams held by an actor, each with
+// a filter deciding which events it gets. Dropping a
+// client's sender ends its stream.
+pub struct Clients<T> {
+    // client id to Client
+    clients: HashMap<String, Client<T>>,
+}
+
+impl<T> Default for Clients<T> {
+    fn default() -> Clients<T> {
+        Clients { clients: HashMap::new() }
+    }
+}
+
+impl<T> Clients<T> {
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    // Clients matching that are still connected
+    pub fn count<F: Fn(&T) -> bool>(&self, matches: F) -> usize {
+        self.clients
+            .values()
+            .filter(|client| !client.sender.is_closed() && matches(&client.filter))
+            .count()
+    }
+
+    pub fn add(&mut self, filter: T, sender: mpsc::Sender<Bytes>) {
+        self.clients.insert(Uuid::new_v4().to_simple().to_string(), Client { filter, sender });
+    }
+
+    pub fn filters_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.clients.values_mut().map(|client| &mut client.filter)
+    }
+
+    pub fn retain<F: Fn(&T) -> bool>(&mut self, keep: F) {
+        self.clients.retain(|_, client| keep(&client.filter));
+    }
+
+    // Send event to the clients it matches, dropping
+    // clients that have gone away
+    pub fn send<F: Fn(&T) -> bool>(&mut self, matches: F, event: &str) {
+        let mut closed = Vec::new();
+        for (id, client) in self.clients.iter_mut() {
+            if !matches(&client.filter) {
+                continue;
+            }
+            if let Err(e) = client.sender.try_send(Bytes::from(event.to_string())) {
+                if e.is_disconnected() {
+                    closed.push(id.clone());
+                }
+            }
+        }
+        for id in closed {
+            self.clients.remove(&id);
+        }
+    }
+
+    pub fn keep_alive(&mut self) {
+        self.send(|_| true, ": keep-alive\n\n");
+    }
+
+    // Send each client its last event, then end the streams
+    pub fn close<F: FnMut(&T) -> Option<String>>(&mut self, mut last_event: F) {
+        for client in self.clients.values_mut() {
+            if let Some(event) = last_event(&client.filter) {
+                let _ = client.sender.try_send(Bytes::from(event));
+            }
+        }
+        self.clients.clear();
+    }
+}
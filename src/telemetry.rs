@@ -0,0 +1,48 @@
+use actix_web::dev::ServiceRequest;
+use tracing::{info_span, Span};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+use crate::config::Config;
+
+// Server logs are filtered with log_filter, in the usual
+// env_logger directive syntax. A log_format of json writes
+// one JSON object per line.
+pub fn init(config: &Config) {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::new(&config.log_filter));
+    match config.log_format == "json" {
+        true => builder.json().init(),
+        false => builder.init(),
+    }
+}
+
+// Longest X-Request-Id taken from a client
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+// Request ids from clients end up in every log line of the
+// request, so only short ids of safe characters are kept
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+// Span around an HTTP request. The request id is taken from
+// X-Request-Id when a proxy sets a valid one. account_id and
+// device_id are recorded once the request is authenticated.
+pub fn request_span(req: &ServiceRequest) -> Span {
+    let request_id = req.headers()
+        .get("X-Request-Id")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| valid_request_id(v))
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_simple().to_string());
+    info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path(),
+        account_id = tracing::field::Empty,
+        device_id = tracing::field::Empty,
+    )
+}
@@ -0,0 +1,341 @@
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{NoClientAuth, ResolvesServerCert, ServerConfig, SignatureScheme};
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use signal_hook::iterator::Signals;
+use tracing::{error, info};
+
+// Serves the certificate most recently loaded from disk, so
+// a renewed certificate is picked up by new connections
+// without restarting and dropping every device
+pub struct ReloadingCert {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<CertifiedKey>,
+}
+
+impl ReloadingCert {
+    pub fn new(cert_path: &str, key_path: &str) -> Result<ReloadingCert, String> {
+        let current = load_certified_key(cert_path, key_path)?;
+        Ok(ReloadingCert {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            current: RwLock::new(current),
+        })
+    }
+
+    // A certificate that fails to load is logged and the
+    // previous one kept
+    pub fn reload(&self) {
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                match self.current.write() {
+                    Ok(mut current) => *current = key,
+                    Err(e) => error!(error = %e, "Error replacing TLS certificate"),
+                }
+                info!(cert_path = %self.cert_path, "Reloaded TLS certificate");
+            },
+            Err(e) => error!(error = %e, cert_path = %self.cert_path, "Error reloading TLS certificate"),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _server_name: Option<webpki::DNSNameRef>, _sigschemes: &[SignatureScheme]) -> Option<CertifiedKey> {
+        self.current.read().ok().map(|key| key.clone())
+    }
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey, String> {
+    let cert_pem = fs::read(cert_path).map_err(|e| format!("unable to open {}: {}", cert_path, e))?;
+    let cert_chain = certs(&mut &cert_pem[..])
+        .map_err(|_| format!("invalid certificate in {}", cert_path))?;
+    if cert_chain.is_empty() {
+        return Err(format!("no certificate in {}", cert_path));
+    }
+
+    // PKCS#8 keys, falling back to PKCS#1 RSA keys
+    let key_pem = fs::read(key_path).map_err(|e| format!("unable to open {}: {}", key_path, e))?;
+    let mut keys = pkcs8_private_keys(&mut &key_pem[..])
+        .map_err(|_| format!("invalid private key in {}", key_path))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut &key_pem[..])
+            .map_err(|_| format!("invalid private key in {}", key_path))?;
+    }
+    let key = match keys.into_iter().next() {
+        Some(k) => k,
+        None => return Err(format!("no private key in {}", key_path)),
+    };
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|_| format!("unsupported private key in {}", key_path))?;
+
+    // rustls doesn't check that the key belongs to the
+    // certificate, a mismatch would only show up as
+    // failed handshakes
+    let matches = X509::from_der(&cert_chain[0].0)
+        .and_then(|cert| cert.public_key())
+        .and_then(|public| PKey::private_key_from_der(&key.0).map(|private| public.public_eq(&private)));
+    match matches {
+        Ok(true) => (),
+        Ok(false) => return Err(format!("private key in {} doesn't match the certificate in {}", key_path, cert_path)),
+        Err(e) => return Err(format!("unable to compare {} with {}: {}", key_path, cert_path, e)),
+    }
+
+    Ok(CertifiedKey::new(cert_chain, Arc::new(signing_key)))
+}
+
+// rustls config serving cert, reloaded from disk on SIGHUP
+pub fn server_config(cert: Arc<ReloadingCert>) -> std::io::Result<ServerConfig> {
+    let signals = Signals::new(&[signal_hook::SIGHUP])?;
+    let reloading = cert.clone();
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            reloading.reload();
+        }
+    });
+
+    Ok(resolving_config(cert))
+}
+
+fn resolving_config(cert: Arc<ReloadingCert>) -> ServerConfig {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = cert;
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::Private;
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::X509NameBuilder;
+    use rustls::{Certificate, ClientConfig, ClientSession, ServerSession, Session, TLSError};
+    use std::ops::Deref;
+    use std::path::{Path, PathBuf};
+    use uuid::Uuid;
+
+    // Self-signed certificate for localhost and its key
+    fn self_signed() -> (X509, Rsa<Private>) {
+        let rsa = Rsa::generate(2048).unwrap();
+        let key = PKey::from_rsa(rsa.clone()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        // webpki only matches names in subjectAltName
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (cert.build(), rsa)
+    }
+
+    fn pkcs8_pem(rsa: &Rsa<Private>) -> Vec<u8> {
+        PKey::from_rsa(rsa.clone()).unwrap().private_key_to_pem_pkcs8().unwrap()
+    }
+
+    // Removed when dropped at the end of the test
+    struct TempFile(PathBuf);
+
+    impl Deref for TempFile {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempFile {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn temp_file(contents: &[u8]) -> TempFile {
+        let path = std::env::temp_dir().join(format!("herd-tls-{}.pem", Uuid::new_v4()));
+        fs::write(&path, contents).unwrap();
+        TempFile(path)
+    }
+
+    fn load(cert: &Path, key: &Path) -> Result<CertifiedKey, String> {
+        load_certified_key(cert.to_str().unwrap(), key.to_str().unwrap())
+    }
+
+    #[test]
+    fn loads_pkcs8_key() {
+        let (cert, rsa) = self_signed();
+        let cert_path = temp_file(&cert.to_pem().unwrap());
+        let key_path = temp_file(&pkcs8_pem(&rsa));
+
+        let loaded = load(&cert_path, &key_path).unwrap();
+        assert_eq!(loaded.cert[0].0, cert.to_der().unwrap());
+    }
+
+    #[test]
+    fn loads_rsa_key() {
+        let (cert, rsa) = self_signed();
+        let cert_path = temp_file(&cert.to_pem().unwrap());
+        let key_pem = rsa.private_key_to_pem().unwrap();
+        assert!(String::from_utf8_lossy(&key_pem).contains("BEGIN RSA PRIVATE KEY"));
+        let key_path = temp_file(&key_pem);
+
+        assert!(load(&cert_path, &key_path).is_ok());
+    }
+
+    #[test]
+    fn rejects_empty_files() {
+        let (cert, rsa) = self_signed();
+        let cert_path = temp_file(&cert.to_pem().unwrap());
+        let key_path = temp_file(&pkcs8_pem(&rsa));
+        let empty_path = temp_file(b"");
+
+        assert!(load(&empty_path, &key_path).is_err());
+        assert!(load(&cert_path, &empty_path).is_err());
+    }
+
+    #[test]
+    fn rejects_swapped_files() {
+        let (cert, rsa) = self_signed();
+        let cert_path = temp_file(&cert.to_pem().unwrap());
+        let key_path = temp_file(&pkcs8_pem(&rsa));
+
+        assert!(load(&key_path, &cert_path).is_err());
+    }
+
+    #[test]
+    fn rejects_key_of_another_certificate() {
+        let (cert, _) = self_signed();
+        let (_, other_rsa) = self_signed();
+        let cert_path = temp_file(&cert.to_pem().unwrap());
+        let key_path = temp_file(&pkcs8_pem(&other_rsa));
+
+        let error = load(&cert_path, &key_path).err().unwrap();
+        assert!(error.contains("doesn't match"));
+    }
+
+    fn current_cert(reloading: &ReloadingCert) -> Vec<u8> {
+        reloading.current.read().unwrap().cert[0].0.clone()
+    }
+
+    #[test]
+    fn reload_swaps_certificate() {
+        let (cert, rsa) = self_signed();
+        let cert_path = temp_file(&cert.to_pem().unwrap());
+        let key_path = temp_file(&pkcs8_pem(&rsa));
+        let reloading = ReloadingCert::new(cert_path.to_str().unwrap(), key_path.to_str().unwrap()).unwrap();
+
+        let (renewed, renewed_rsa) = self_signed();
+        fs::write(&cert_path, renewed.to_pem().unwrap()).unwrap();
+        fs::write(&key_path, pkcs8_pem(&renewed_rsa)).unwrap();
+        reloading.reload();
+
+        assert_eq!(current_cert(&reloading), renewed.to_der().unwrap());
+    }
+
+    #[test]
+    fn reload_keeps_certificate_when_new_one_is_bad() {
+        let (cert, rsa) = self_signed();
+        let cert_path = temp_file(&cert.to_pem().unwrap());
+        let key_path = temp_file(&pkcs8_pem(&rsa));
+        let reloading = ReloadingCert::new(cert_path.to_str().unwrap(), key_path.to_str().unwrap()).unwrap();
+
+        // Renewed certificate written before its key
+        let (renewed, _) = self_signed();
+        fs::write(&cert_path, renewed.to_pem().unwrap()).unwrap();
+        reloading.reload();
+        assert_eq!(current_cert(&reloading), cert.to_der().unwrap());
+
+        fs::write(&cert_path, b"").unwrap();
+        reloading.reload();
+        assert_eq!(current_cert(&reloading), cert.to_der().unwrap());
+    }
+
+    // Move whatever from has to send into to
+    fn transfer(from: &mut dyn Session, to: &mut dyn Session) -> Result<(), TLSError> {
+        let mut buffer = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut buffer).unwrap();
+        }
+        let mut pending = &buffer[..];
+        while !pending.is_empty() {
+            to.read_tls(&mut pending).unwrap();
+            to.process_new_packets()?;
+        }
+        Ok(())
+    }
+
+    // Handshake with a client trusting only trusted,
+    // returns the certificate the server presented
+    fn handshake(reloading: Arc<ReloadingCert>, trusted: &X509) -> Result<Vec<u8>, TLSError> {
+        let mut client_config = ClientConfig::new();
+        client_config.root_store.add(&Certificate(trusted.to_der().unwrap())).unwrap();
+        let name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut client = ClientSession::new(&Arc::new(client_config), name);
+        let mut server = ServerSession::new(&Arc::new(resolving_config(reloading)));
+
+        for _ in 0..10 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                break;
+            }
+            transfer(&mut client, &mut server)?;
+            transfer(&mut server, &mut client)?;
+        }
+        assert!(!client.is_handshaking(), "handshake didn't finish");
+        Ok(client.get_peer_certificates().unwrap()[0].0.clone())
+    }
+
+    #[test]
+    fn handshake_with_certificate() {
+        let (cert, rsa) = self_signed();
+        let cert_path = temp_file(&cert.to_pem().unwrap());
+        let key_path = temp_file(&pkcs8_pem(&rsa));
+        let reloading = Arc::new(ReloadingCert::new(cert_path.to_str().unwrap(), key_path.to_str().unwrap()).unwrap());
+
+        assert_eq!(handshake(reloading.clone(), &cert).unwrap(), cert.to_der().unwrap());
+        let (other, _) = self_signed();
+        assert!(handshake(reloading, &other).is_err());
+    }
+
+    #[test]
+    fn handshake_with_reloaded_certificate() {
+        let (cert, rsa) = self_signed();
+        let cert_path = temp_file(&cert.to_pem().unwrap());
+        let key_path = temp_file(&pkcs8_pem(&rsa));
+        let reloading = Arc::new(ReloadingCert::new(cert_path.to_str().unwrap(), key_path.to_str().unwrap()).unwrap());
+        assert!(handshake(reloading.clone(), &cert).is_ok());
+
+        let (renewed, renewed_rsa) = self_signed();
+        fs::write(&cert_path, renewed.to_pem().unwrap()).unwrap();
+        fs::write(&key_path, pkcs8_pem(&renewed_rsa)).unwrap();
+        reloading.reload();
+
+        assert_eq!(handshake(reloading.clone(), &renewed).unwrap(), renewed.to_der().unwrap());
+        assert!(handshake(reloading, &cert).is_err());
+    }
+}
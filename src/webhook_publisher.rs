@@ -1,40 +1,54 @@
 use actix;
 use actix::prelude::*;
-use std::time::{Duration};
+use std::time::{Duration, Instant};
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use futures::future::{join_all};
+use tracing::{debug, error, info, info_span};
+use tracing_futures::Instrument;
 
 use crate::db;
 use crate::db::DbPool;
 
-use crate::publisher::{PublishMessage};
+use crate::publisher::{PublishMessage, RemoveTopic};
+use crate::metrics;
 
-const WEBHOOK_UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+// Number of messages whose webhooks are still being
+// delivered, polled while draining on shutdown
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct InFlightDeliveries();
 
 pub struct WebhookPublisher {
     pool: DbPool,
-    topics: HashMap<String, HashSet<String>>
+    topics: HashMap<String, HashSet<String>>,
+    refresh_interval: Duration,
+    // Shared with the spawned delivery futures
+    in_flight: Arc<AtomicUsize>,
 }
 
 impl Actor for WebhookPublisher {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
-        println!("Webhook publisher started");
+        info!("Webhook publisher started");
         WebhookPublisher::refresh_webhooks(self);
         self.webhook_refresh_interval(ctx);
     }
 
     fn stopped(&mut self, _ctx: &mut Context<Self>) {
-        println!("Webhook publisher stopped.");
+        info!("Webhook publisher stopped");
     }
 }
 
 impl WebhookPublisher {
-    pub fn initialize(pool: DbPool) -> WebhookPublisher {
+    pub fn initialize(pool: DbPool, refresh_interval: Duration) -> WebhookPublisher {
         WebhookPublisher {
             pool,
-            topics: HashMap::new()
+            topics: HashMap::new(),
+            refresh_interval,
+            in_flight: Arc::new(AtomicUsize::new(0)),
         }
     }
 
@@ -45,29 +59,27 @@ impl WebhookPublisher {
                 let topics = match maybe_topics {
                     Ok(t) => t,
                     Err(e) => {
-                        println!("Error getting topics: {:?}", e);
+                        error!(error = ?e, "Error getting topics");
                         return;
                     },
                 };
+                // Rebuild from scratch so deleted webhooks
+                // and topics don't linger
+                let mut webhook_topics: HashMap<String, HashSet<String>> = HashMap::new();
                 for item in topics {
-                    match web.topics.get_mut(&item.0) {
-                        Some(set) => {
-                            set.insert(item.1);
-                        },
-                        None => {
-                            let mut set = HashSet::new();
-                            set.insert(item.1);
-                            web.topics.insert(item.0.clone(), set);
-                        }
-                    }
+                    webhook_topics
+                        .entry(item.0)
+                        .or_insert_with(HashSet::new)
+                        .insert(item.1);
                 }
+                web.topics = webhook_topics;
             }
-            Err(e) => println!("Error getting db connection: {:?}", e),
+            Err(e) => error!(error = ?e, "Error getting db connection"),
         }
     }
 
     fn webhook_refresh_interval(&self, ctx: &mut <Self as Actor>::Context) {
-        ctx.run_interval(WEBHOOK_UPDATE_INTERVAL, |act, _ctx| {
+        ctx.run_interval(self.refresh_interval, |act, _ctx| {
             WebhookPublisher::refresh_webhooks(act);
         });
     }
@@ -77,6 +89,8 @@ impl Handler<PublishMessage> for WebhookPublisher {
     type Result = ();
 
     fn handle(&mut self, msg: PublishMessage, _ctx: &mut Context<Self>) -> Self::Result {
+        let span = info_span!("webhook", account_id = %msg.account_id);
+        let _enter = span.enter();
         let mut webhooks: HashSet<String> = HashSet::new();
         // Find all the webhooks that should receive a message
         for topic in msg.message.topics.iter() {
@@ -95,27 +109,69 @@ impl Handler<PublishMessage> for WebhookPublisher {
         let serialized_message = match message {
             Ok(m) => m,
             Err(e) => {
-                println!("Error serializing message: {:?}", e);
+                error!(error = ?e, "Error serializing message");
                 return;
             },
         };
 
+        if webhooks.is_empty() {
+            return;
+        }
+
+        let in_flight = self.in_flight.clone();
+        in_flight.fetch_add(1, Ordering::SeqCst);
         actix::spawn(async move {
             let mut requests = Vec::new();
             for url in webhooks {
-                println!("Webhook sending to {:?}", url);
-                requests.push(async {
-                    println!("Hey: {}", serialized_message);
+                let body = &serialized_message;
+                requests.push(async move {
+                    let start = Instant::now();
+                    let result = deliver(&url, body).await;
+                    metrics::WEBHOOK_LATENCY.observe(start.elapsed().as_secs_f64());
+                    if let Err(e) = result {
+                        metrics::WEBHOOK_FAILURES.inc();
+                        error!(error = %e, url = %url, "Webhook delivery failed");
+                    }
                 });
-                // let client = Client::default();
-                // requests.push(
-                //     client.post(url)
-                //         .header("Content-Type", "application/json")
-                //         .header("User-Agent", "Actix-web")
-                //         .send_body(serialized_message.clone())
-                //     );
             }
             join_all(requests).await;
-        });
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+        }.instrument(span.clone()));
+    }
+}
+
+async fn deliver(url: &str, body: &str) -> Result<(), String> {
+    debug!(url = %url, "Webhook sending");
+    // Payloads are customer data, only their size is logged
+    debug!(bytes = body.len(), "Webhook body");
+    // let client = Client::default();
+    // client.post(url)
+    //     .header("Content-Type", "application/json")
+    //     .header("User-Agent", "Actix-web")
+    //     .send_body(body.to_string())
+    //     .await
+    //     .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+impl Handler<RemoveTopic> for WebhookPublisher {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveTopic, _ctx: &mut Context<Self>) -> Self::Result {
+        self.topics.remove(&msg.topic_id);
+    }
+}
+
+impl Handler<InFlightDeliveries> for WebhookPublisher {
+    type Result = usize;
+
+    fn handle(&mut self, _msg: InFlightDeliveries, _ctx: &mut Context<Self>) -> Self::Result {
+        self.in_flight.load(Ordering::SeqCst)
     }
 }
+
+impl Handler<metrics::MailboxProbe> for WebhookPublisher {
+    type Result = ();
+
+    fn handle(&mut self, _msg: metrics::MailboxProbe, _ctx: &mut Context<Self>) -> Self::Result {}
+}
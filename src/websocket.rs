@@ -1,35 +1,60 @@
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use actix::prelude::*;
 use actix_web_actors::ws;
 use actix_web::web;
 use serde_json::{json, Result as SerdeResult, Value};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use tracing::{error, info, info_span, warn, Span};
 
 use crate::publisher;
 use crate::rate_limiter::RateLimit;
 use crate::db::DbPool;
 use crate::logging;
-
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-/// How long before lack of client response causes a timeout
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+use crate::log_writer;
+use crate::log_writer::{LogWriter};
+use crate::shadow;
+use crate::config::Config;
+use crate::metrics;
 
 pub struct WebSocket {
     account_id: String, // The account associated with the connection
     device_id: String, // The unique device (not type) connected
     device_type_id: String,
+    session_id: String, // Unique to this connection
+    // Published by the publisher if the connection
+    // ends without a clean close
+    will: Option<publisher::Will>,
+    // Why the connection ended, reported to the
+    // publisher once the actor stops
+    disconnect_reason: publisher::DisconnectReason,
+    peer_address: Option<String>,
+    // Messages received from and delivered to the
+    // device, recorded with the session
+    messages_in: i64,
+    messages_out: i64,
     hb: Instant,
+    heartbeat_interval: Duration,
+    // How long before lack of client response causes a timeout
+    client_timeout: Duration,
     publisher: Addr<publisher::Publisher>,
     rate_limit_struct: RateLimit,
     rate_limit: i32,
     pool: web::Data<DbPool>,
+    log_writer: Addr<LogWriter>,
+    // Carries the connection's ids into every server log
+    // written while handling it
+    span: Span,
 }
 
 impl Actor for WebSocket {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        let span = self.span.clone();
+        let _enter = span.enter();
         self.hb(ctx);
+        self.record_session_start();
 
         let addr = ctx.address();
         self.publisher
@@ -37,20 +62,27 @@ impl Actor for WebSocket {
                 account_id: self.account_id.clone(),
                 device_id: self.device_id.clone(),
                 device_type_id: self.device_type_id.clone(),
+                session_id: self.session_id.clone(),
+                will: self.will.clone(),
                 addr,
             })
             // TODO: no clue what the rest of this function does
             // look into it
             .into_actor(self)
-            .then(|res, _, ctx| {
+            .then(|res, act, ctx| {
                 match res {
                     Ok(res) => {
                         match res {
-                            Ok(_) => (),
-                            Err(_) => {
+                            // Let the device know about state changes
+                            // requested while it was offline
+                            Ok(_) => act.send_shadow_delta(ctx),
+                            Err(e) => {
                                 // if the result of the publisher
                                 // connect handler is an error, close
                                 // the connection
+                                if e == publisher::MAX_CONNECTIONS_ERROR {
+                                    act.disconnect_reason = publisher::DisconnectReason::MaxConnections;
+                                }
                                 ctx.stop();
                             },
                         };
@@ -62,6 +94,23 @@ impl Actor for WebSocket {
             })
             .wait(ctx);
     }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        // Every way a connection can end passes through here,
+        // including heartbeat timeouts and dropped connections
+        // that never sent a Close frame
+        self.publisher
+            .do_send(publisher::Disconnect {
+                account_id: self.account_id.clone(),
+                device_id: self.device_id.clone(),
+                device_type_id: self.device_type_id.clone(),
+                session_id: self.session_id.clone(),
+                reason: self.disconnect_reason,
+            });
+        self.record_session_end();
+    }
 }
 
 impl WebSocket {
@@ -69,15 +118,35 @@ impl WebSocket {
         account_id: String,
         device_id: String,
         device_type_id: String,
+        will: Option<publisher::Will>,
+        peer_address: Option<String>,
         max_requests_per_minute: i32,
         publisher: Addr<publisher::Publisher>,
         pool: web::Data<DbPool>,
+        log_writer: Addr<LogWriter>,
+        config: &Config,
     ) -> Self {
+        let session_id = Uuid::new_v4().to_simple().to_string();
+        let span = info_span!(
+            "websocket",
+            account_id = %account_id,
+            device_id = %device_id,
+            session_id = %session_id,
+        );
         Self {
             account_id,
             device_id,
             device_type_id,
+            session_id,
+            will,
+            // Until we hear otherwise, the connection was lost
+            disconnect_reason: publisher::DisconnectReason::ConnectionLost,
+            peer_address,
+            messages_in: 0,
+            messages_out: 0,
             hb: Instant::now(),
+            heartbeat_interval: config.heartbeat_interval(),
+            client_timeout: config.client_timeout(),
             publisher,
             rate_limit_struct: RateLimit::new(),
             // TODO: this value should come from the api server
@@ -86,14 +155,119 @@ impl WebSocket {
             // it should be reflected without having to restart
             // connection.
             rate_limit: max_requests_per_minute,
-            pool
+            pool,
+            log_writer,
+            span,
+        }
+    }
+
+    // Session rows are written by the log writer, the
+    // start and end go through the same mailbox in order.
+    // do_send so they aren't dropped with a full queue.
+    fn record_session_start(&self) {
+        self.log_writer.do_send(log_writer::SessionStarted {
+            session_id: self.session_id.clone(),
+            account_id: self.account_id.clone(),
+            device_id: self.device_id.clone(),
+            device_type_id: self.device_type_id.clone(),
+            peer_address: self.peer_address.clone(),
+        });
+    }
+
+    fn record_session_end(&self) {
+        self.log_writer.do_send(log_writer::SessionEnded {
+            session_id: self.session_id.clone(),
+            disconnected_at: SystemTime::now(),
+            close_reason: self.disconnect_reason.as_str(),
+            messages_in: self.messages_in,
+            messages_out: self.messages_out,
+        });
+    }
+
+    fn send_shadow_delta(&self, ctx: &mut <Self as Actor>::Context) {
+        let conn = match self.pool.get() {
+            Ok(c) => c,
+            Err(e) => {
+                error!(error = ?e, "Error getting db connection");
+                return;
+            },
+        };
+        match shadow::get_shadow(&self.account_id, &self.device_id, &conn) {
+            Ok(s) if s.has_delta() => {
+                send_event(&shadow::ShadowEvent::ShadowDelta {
+                    version: s.version,
+                    state: s.delta,
+                }, ctx);
+            },
+            Ok(_) => (),
+            Err(e) => error!(error = ?e, "Error getting device shadow"),
+        }
+    }
+
+    fn report_state(&self, state: Value, version: Option<i64>, ctx: &mut <Self as Actor>::Context) {
+        let conn = match self.pool.get() {
+            Ok(c) => c,
+            Err(e) => {
+                error!(error = ?e, "Error getting db connection");
+                return;
+            },
+        };
+        // Merging anything else would replace the
+        // whole reported document
+        if !state.is_object() {
+            let current = shadow::get_shadow(&self.account_id, &self.device_id, &conn)
+                .map_or(0, |s| s.version);
+            send_event(&shadow::ShadowEvent::ShadowRejected {
+                version: current,
+                reason: "state must be a JSON object".to_string(),
+            }, ctx);
+            return;
+        }
+        let result = shadow::update_shadow(
+            &self.account_id,
+            &self.device_id,
+            shadow::ShadowDocument::Reported,
+            &state,
+            version,
+            &conn,
+        );
+        match result {
+            Ok(s) => {
+                if s.has_delta() {
+                    send_event(&shadow::ShadowEvent::ShadowDelta {
+                        version: s.version,
+                        state: s.delta,
+                    }, ctx);
+                }
+            },
+            Err(shadow::ShadowError::VersionConflict(current)) => {
+                send_event(&shadow::ShadowEvent::ShadowRejected {
+                    version: current,
+                    reason: "version conflict".to_string(),
+                }, ctx);
+            },
+            Err(e) => {
+                logging::log(
+                    &self.account_id,
+                    logging::LogLevel::Error,
+                    json!({
+                        "device_id": self.device_id,
+                        "device_type_id": self.device_type_id,
+                        "error": e.to_string(),
+                    }),
+                    &self.log_writer,
+                );
+            },
         }
     }
 
     fn hb(&self, ctx: &mut <Self as Actor>::Context) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
-                println!("Websocket Client heartbeat failed, disconnecting.");
+        ctx.run_interval(self.heartbeat_interval, |act, ctx| {
+            let span = act.span.clone();
+            let _enter = span.enter();
+            if Instant::now().duration_since(act.hb) > act.client_timeout {
+                info!("Websocket client heartbeat failed, disconnecting");
+                act.disconnect_reason = publisher::DisconnectReason::HeartbeatTimeout;
                 ctx.stop();
                 return;
             }
@@ -125,33 +299,86 @@ pub enum Event {
     },
     Register {
         topics: Vec<String>
+    },
+    ReportState {
+        state: Value,
+        // The shadow version the device last saw
+        version: Option<i64>,
     }
 }
 
+fn send_event<T: Serialize>(event: &T, ctx: &mut ws::WebsocketContext<WebSocket>) {
+    match serde_json::to_string(event) {
+        Ok(m) => ctx.text(m),
+        Err(e) => error!(error = ?e, "Error serializing message"),
+    };
+}
+
 impl Handler<publisher::PublishMessage> for WebSocket {
     type Result = ();
 
     fn handle(&mut self, msg: publisher::PublishMessage, ctx: &mut ws::WebsocketContext<Self>) -> Self::Result {
+        let span = self.span.clone();
+        let _enter = span.enter();
         match serde_json::to_string(&msg) {
-            Ok(m) => ctx.text(m),
-            Err(e) => println!("Error serializing message: {:?}", e),
+            Ok(m) => {
+                self.messages_out += 1;
+                ctx.text(m);
+            },
+            Err(e) => error!(error = ?e, "Error serializing message"),
         };
     }
 }
 
-impl Handler<publisher::Shutdown> for WebSocket {
+impl Handler<publisher::Reconnect> for WebSocket {
     type Result = ();
 
-    fn handle(&mut self, _msg: publisher::Shutdown, ctx: &mut ws::WebsocketContext<Self>) -> Self::Result {
+    fn handle(&mut self, msg: publisher::Reconnect, ctx: &mut ws::WebsocketContext<Self>) -> Self::Result {
         // Notify the client that the server is closing
         // the connection because a new deployment is
-        //
+        // happening, and when to come back
+        self.disconnect_reason = publisher::DisconnectReason::ServerShutdown;
         let close_data = ws::CloseReason {
             code: ws::CloseCode::Restart,
-            description: Some("new server being deployed".to_string()),
+            description: Some(json!({
+                "reason": "new server being deployed",
+                "reconnect_after_ms": msg.reconnect_after.as_millis() as u64,
+            }).to_string()),
+        };
+
+        ctx.close(Some(close_data));
+        ctx.stop();
+    }
+}
+
+impl Handler<publisher::CloseSession> for WebSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: publisher::CloseSession, ctx: &mut ws::WebsocketContext<Self>) -> Self::Result {
+        self.disconnect_reason = publisher::DisconnectReason::ServerClose;
+        let close_data = ws::CloseReason {
+            code: msg.code,
+            description: Some(msg.description),
         };
 
         ctx.close(Some(close_data));
+        ctx.stop();
+    }
+}
+
+impl Handler<shadow::ShadowEvent> for WebSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: shadow::ShadowEvent, ctx: &mut ws::WebsocketContext<Self>) -> Self::Result {
+        send_event(&msg, ctx);
+    }
+}
+
+impl Handler<publisher::UpdateRateLimit> for WebSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: publisher::UpdateRateLimit, _ctx: &mut ws::WebsocketContext<Self>) -> Self::Result {
+        self.rate_limit = msg.0;
     }
 }
 
@@ -161,6 +388,8 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocket {
         msg: Result<ws::Message, ws::ProtocolError>,
         ctx: &mut Self::Context,
     ) {
+        let span = self.span.clone();
+        let _enter = span.enter();
         match msg {
             Ok(ws::Message::Ping(msg)) => {
                 self.hb = Instant::now();
@@ -174,6 +403,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocket {
                 // the device level, not the account level.
                 self.rate_limit_struct.update_request_count();
                 if self.rate_limit_struct.requests > self.rate_limit {
+                    metrics::RATE_LIMIT_REJECTIONS.with_label_values(&[metrics::account_label(&self.account_id)]).inc();
                     return;
                 }
 
@@ -188,6 +418,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocket {
                                 topics,
                                 data,
                             } => {
+                                self.messages_in += 1;
                                 let sender = publisher::Sender::Device {
                                     device_id: self.device_id.clone(),
                                     device_type_id: self.device_type_id.clone(),
@@ -212,6 +443,9 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocket {
                                         topics,
                                     });
                             },
+                            Event::ReportState { state, version } => {
+                                self.report_state(state, version, ctx);
+                            },
                         }
                     },
                     Err(err) => {
@@ -223,24 +457,23 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocket {
                                 "device_type_id": self.device_type_id,
                                 "error": err.to_string(),
                             }),
-                            &self.pool,
+                            &self.log_writer,
                         );
                     }
                 }
             },
             Ok(ws::Message::Close(_)) => {
-                self.publisher
-                    .do_send(publisher::Disconnect {
-                        account_id: self.account_id.clone(),
-                        device_id: self.device_id.clone(),
-                        device_type_id: self.device_type_id.clone(),
-                    });
+                // A device answering the server's close keeps
+                // the server's reason
+                if let publisher::DisconnectReason::ConnectionLost = self.disconnect_reason {
+                    self.disconnect_reason = publisher::DisconnectReason::ClientClose;
+                }
                 ctx.stop()
             },
-            Ok(ws::Message::Binary(_)) => println!("Received binary data. Binary data is not supported."),
+            Ok(ws::Message::Binary(_)) => warn!("Received binary data, binary data is not supported"),
             _ => {
                 // TODO: return a useful error message
-                println!("Bad formed data from {}", self.account_id);
+                warn!("Badly formed data");
             },
         }
     }